@@ -0,0 +1,50 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use merge::{infrastructure::gen_zipf_tables, join::{partitioned_mpsm_into, skew_aware_mpsm_into}, sink::CountSink};
+use rand::{rngs::StdRng, SeedableRng};
+
+const DIMENSION_SIZE : usize = 1 << 16;
+const FACT_SIZE : usize = 1 << 22;
+const THREAD_COUNT : usize = 8;
+
+const ZIPF_EXPONENTS : [f64; 4] = [0.0, 0.5, 1.0, 1.5];
+
+fn bench_skewed_mpsm(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skewed_mpsm");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((DIMENSION_SIZE + FACT_SIZE) as u64));
+
+    for &s in &ZIPF_EXPONENTS {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = gen_zipf_tables(DIMENSION_SIZE, FACT_SIZE, s, &mut rng);
+
+        group.bench_with_input(BenchmarkId::new("partitioned_mpsm", s), &s, |b, _| {
+            b.iter_batched(
+                || {
+                    (fact.clone(), dimension.clone())
+                },
+                |input| {
+                    let out = partitioned_mpsm_into(black_box(input.0), black_box(input.1), THREAD_COUNT, CountSink::default);
+                    black_box(out);
+                },
+                BatchSize::LargeInput
+            );
+        });
+
+        group.bench_with_input(BenchmarkId::new("skew_aware_mpsm", s), &s, |b, _| {
+            b.iter_batched(
+                || {
+                    (fact.clone(), dimension.clone())
+                },
+                |input| {
+                    let out = skew_aware_mpsm_into(black_box(input.0), black_box(input.1), THREAD_COUNT, CountSink::default);
+                    black_box(out);
+                },
+                BatchSize::LargeInput
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_skewed_mpsm);
+criterion_main!(benches);
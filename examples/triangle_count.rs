@@ -0,0 +1,24 @@
+// Counts the triangles of a random graph with the leapfrog triejoin.
+//
+//     cargo run --release --example triangle_count -- [vertices] [edges] [threads]
+
+use std::{env, time::Instant};
+
+use merge::{infrastructure::gen_edges, triejoin::count_triangles};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn main() {
+    let args: Vec<usize> = env::args().skip(1)
+        .map(|a| a.parse().expect("arguments must be numbers"))
+        .collect();
+    let vertex_count = args.first().copied().unwrap_or(1 << 16);
+    let edge_count = args.get(1).copied().unwrap_or(1 << 20);
+    let thread_count = args.get(2).copied().unwrap_or(8);
+
+    let mut rng = StdRng::seed_from_u64(101);
+    let edges = gen_edges(vertex_count as u64, edge_count, &mut rng);
+
+    let start = Instant::now();
+    let triangles = count_triangles(&edges, thread_count);
+    println!("{vertex_count} vertices, {edge_count} edges: {triangles} triangles in {:?}", start.elapsed());
+}
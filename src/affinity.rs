@@ -0,0 +1,279 @@
+use std::{cell::RefCell, fs, io, path::Path, sync::Arc, thread::{Scope, ScopedJoinHandle}};
+
+// Opt-in placement of the worker threads of the parallel phases. By default
+// the OS schedules the workers and is free to migrate them. Inside
+// with_placement, worker i of every phase started from the calling thread is
+// pinned to the i-th CPU of the placement order (modulo the number of CPUs).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    #[default]
+    Os,
+    // Fill the hardware threads of a core, then the cores of a socket, before
+    // moving on. Workers share caches.
+    Compact,
+    // One worker per core, alternating between sockets, before any core gets
+    // a second worker. Workers get the most cache and memory bandwidth.
+    Scatter
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cpu {
+    pub id: usize,
+    pub core: usize,
+    pub package: usize
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub cpus: Vec<Cpu>
+}
+
+impl CpuTopology {
+    pub fn detect() -> io::Result<CpuTopology> {
+        CpuTopology::from_sysfs(Path::new("/sys/devices/system/cpu"))
+    }
+
+    // Reads the online CPUs and their core and package ids from a sysfs cpu
+    // directory. CPUs without topology files count as cores of their own.
+    pub fn from_sysfs(root: &Path) -> io::Result<CpuTopology> {
+        let online = parse_cpu_list(fs::read_to_string(root.join("online"))?.trim())?;
+
+        let read_id = |cpu: usize, file: &str| -> Option<usize> {
+            let path = root.join(format!("cpu{cpu}/topology/{file}"));
+            fs::read_to_string(path).ok()?.trim().parse().ok()
+        };
+
+        let cpus = online.into_iter()
+            .map(|id| Cpu {
+                id,
+                core: read_id(id, "core_id").unwrap_or(id),
+                package: read_id(id, "physical_package_id").unwrap_or(0)
+            })
+            .collect();
+        Ok(CpuTopology {cpus})
+    }
+
+    // CPU ids in the order workers are assigned to them. Empty for Os.
+    pub fn order(&self, placement: Placement) -> Vec<usize> {
+        let mut cpus = self.cpus.clone();
+        cpus.sort_by_key(|c| (c.package, c.core, c.id));
+
+        match placement {
+            Placement::Os => Vec::new(),
+            Placement::Compact => cpus.iter().map(|c| c.id).collect(),
+            Placement::Scatter => {
+                // Rank of each CPU among the hardware threads of its core, and
+                // of its core among the cores of its package
+                let mut ranked = Vec::with_capacity(cpus.len());
+                let (mut smt, mut core_rank) = (0, 0);
+                for (i, c) in cpus.iter().enumerate() {
+                    if i > 0 && cpus[i - 1].package != c.package {
+                        (smt, core_rank) = (0, 0);
+                    } else if i > 0 && cpus[i - 1].core != c.core {
+                        (smt, core_rank) = (0, core_rank + 1);
+                    } else if i > 0 {
+                        smt += 1;
+                    }
+                    ranked.push((smt, core_rank, c.package, c.id));
+                }
+                ranked.sort_unstable();
+                ranked.into_iter().map(|r| r.3).collect()
+            }
+        }
+    }
+}
+
+// Parses a sysfs CPU list such as "0-3,8,10-11".
+pub fn parse_cpu_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid cpu list {list:?}"));
+
+    let mut cpus = Vec::new();
+    for part in list.split(',').filter(|p| !p.is_empty()) {
+        let (lo, hi) = part.split_once('-').unwrap_or((part, part));
+        let lo: usize = lo.parse().map_err(|_| invalid())?;
+        let hi: usize = hi.parse().map_err(|_| invalid())?;
+        if lo > hi {
+            return Err(invalid());
+        }
+        cpus.extend(lo..=hi);
+    }
+    Ok(cpus)
+}
+
+thread_local! {
+    static WORKER_CPUS: RefCell<Option<Arc<[usize]>>> = const { RefCell::new(None) };
+}
+
+// Runs f with the workers started from this thread pinned in the given
+// placement order. CPUs outside the affinity mask of the process are skipped.
+pub fn with_placement<R>(placement: Placement, f: impl FnOnce() -> R) -> io::Result<R> {
+    if placement == Placement::Os {
+        return Ok(f());
+    }
+
+    let allowed = allowed_cpus()?;
+    let cpus: Vec<usize> = CpuTopology::detect()?.order(placement).into_iter()
+        .filter(|c| allowed.contains(c))
+        .collect();
+    if cpus.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no online cpu in the affinity mask"));
+    }
+    Ok(with_cpus(cpus, f))
+}
+
+// Runs f with worker i of every phase pinned to cpus[i % cpus.len()].
+pub fn with_cpus<R>(cpus: Vec<usize>, f: impl FnOnce() -> R) -> R {
+    assert!(!cpus.is_empty());
+
+    let previous = WORKER_CPUS.with(|w| w.replace(Some(cpus.into())));
+    // Restore the previous placement even if f panics
+    struct Restore(Option<Arc<[usize]>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            WORKER_CPUS.with(|w| *w.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(previous);
+
+    f()
+}
+
+// CPU that worker `worker` is pinned to under the placement of this thread.
+pub fn worker_cpu(worker: usize) -> Option<usize> {
+    WORKER_CPUS.with(|w| w.borrow().as_ref().map(|cpus| cpus[worker % cpus.len()]))
+}
+
+// Scope::spawn for worker `worker` of a parallel phase. The thread pins itself
+// before running f when a placement is active on the spawning thread.
+pub fn spawn<'scope, T, F>(s: &'scope Scope<'scope, '_>, worker: usize, f: F) -> ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope
+{
+    let cpu = worker_cpu(worker);
+    s.spawn(move || {
+        if let Some(cpu) = cpu {
+            // The CPU was checked against the affinity mask of the process. If
+            // it has been taken away since, the worker runs unpinned.
+            let _ = pin_current_thread(cpu);
+        }
+        f()
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize).filter(|c| libc::CPU_ISSET(*c, &set)).collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread pinning needs sched_setaffinity"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread pinning needs sched_setaffinity"))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(target_os = "linux")]
+    use std::thread;
+
+    #[cfg(target_os = "linux")]
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[cfg(target_os = "linux")]
+    use crate::{infrastructure, join, tuples::Joined};
+
+    use super::*;
+
+    #[test]
+    fn parse_cpu_list_test() {
+        assert_eq!(parse_cpu_list("0-3,6,8-9").unwrap(), vec![0, 1, 2, 3, 6, 8, 9]);
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn placement_orders() {
+        // 2 packages of 2 cores with 2 hardware threads each, numbered the way
+        // Linux numbers them: the second hardware threads come last.
+        let root = std::env::temp_dir().join(format!("mpsm-sysfs-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("online"), "0-7\n").unwrap();
+        for id in 0..8 {
+            let dir = root.join(format!("cpu{id}/topology"));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("core_id"), format!("{}\n", id % 2)).unwrap();
+            fs::write(dir.join("physical_package_id"), format!("{}\n", (id / 2) % 2)).unwrap();
+        }
+
+        let topology = CpuTopology::from_sysfs(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(topology.cpus.len(), 8);
+        assert_eq!(topology.order(Placement::Os), Vec::<usize>::new());
+        assert_eq!(topology.order(Placement::Compact), vec![0, 4, 1, 5, 2, 6, 3, 7]);
+        assert_eq!(topology.order(Placement::Scatter), vec![0, 2, 1, 3, 4, 6, 5, 7]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pinned_workers_run_on_their_cpu() {
+        let allowed = allowed_cpus().unwrap();
+        let cpus = vec![allowed[0]];
+
+        let seen = with_cpus(cpus, || {
+            thread::scope(|s| {
+                let handles: Vec<_> = (0..3)
+                    .map(|i| spawn(s, i, || allowed_cpus().unwrap()))
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+            })
+        });
+        for mask in seen {
+            assert_eq!(mask, vec![allowed[0]]);
+        }
+        assert_eq!(worker_cpu(0), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn placed_mpsm_joins() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+        let nl_output = join::nested_loop_join(&lt, &rt);
+
+        for placement in [Placement::Compact, Placement::Scatter] {
+            let outputs = with_placement(placement, || {
+                (join::basic_mpsm(lt.clone(), rt.clone(), 4), join::partitioned_mpsm(lt.clone(), rt.clone(), 4))
+            }).unwrap();
+
+            let basic = outputs.0.into_iter().flatten().collect::<Vec<Joined>>();
+            let partitioned = outputs.1.into_iter().flatten().collect::<Vec<Joined>>();
+            assert!(infrastructure::table_eq(&nl_output, &basic), "{placement:?}");
+            assert!(infrastructure::table_eq(&nl_output, &partitioned), "{placement:?}");
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::thread;
+
+use crate::{hash_join::{self, RadixConfig}, join, late::RowIdSink, sink::{self, ChecksumSink, CountSink}, tuples::{Joined, RowIdPair, Tuple}};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    // Keep every joined tuple, grouped by the worker that produced it
+    Materialize,
+    // Only report the number of joined tuples
+    Count,
+    // Report the number of joined tuples and an order independent checksum
+    Checksum,
+    // Late materialization: the input payloads are row ids (see
+    // late::key_row_ids), and every joined tuple is kept as a pair of them
+    RowIds
+}
+
+#[derive(Clone, Debug)]
+pub struct JoinConfig {
+    pub thread_count: usize,
+    pub output_mode: OutputMode
+}
+
+impl JoinConfig {
+    pub fn new(thread_count: usize, output_mode: OutputMode) -> JoinConfig {
+        assert!(thread_count > 0);
+        JoinConfig {thread_count, output_mode}
+    }
+}
+
+impl Default for JoinConfig {
+    fn default() -> JoinConfig {
+        let thread_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        JoinConfig::new(thread_count, OutputMode::Materialize)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinResult {
+    // One output vector per worker. Sequential joins produce a single vector.
+    Rows(Vec<Vec<Joined>>),
+    Count(usize),
+    Checksum(ChecksumSink),
+    // One output vector per worker, as for Rows
+    RowIds(Vec<Vec<RowIdPair>>)
+}
+
+impl JoinResult {
+    fn from_counts(sinks: Vec<CountSink>) -> JoinResult {
+        JoinResult::Count(sink::combine_all(sinks).count as usize)
+    }
+
+    fn from_checksums(sinks: Vec<ChecksumSink>) -> JoinResult {
+        JoinResult::Checksum(sink::combine_all(sinks))
+    }
+
+    fn from_row_ids(sinks: Vec<RowIdSink<Vec<RowIdPair>>>) -> JoinResult {
+        JoinResult::RowIds(sinks.into_iter().map(RowIdSink::into_inner).collect())
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            JoinResult::Rows(rows) => rows.iter().map(|r| r.len()).sum(),
+            JoinResult::Count(n) => *n,
+            JoinResult::Checksum(c) => c.count as usize,
+            JoinResult::RowIds(pairs) => pairs.iter().map(|p| p.len()).sum()
+        }
+    }
+
+    // Flattens the per-worker outputs. Returns None for all but row results.
+    pub fn into_rows(self) -> Option<Vec<Joined>> {
+        match self {
+            JoinResult::Rows(rows) => Some(rows.into_iter().flatten().collect()),
+            JoinResult::Count(_) | JoinResult::Checksum(_) | JoinResult::RowIds(_) => None
+        }
+    }
+
+    // Flattens the per-worker row id pairs. Returns None for all but row id results.
+    pub fn into_row_ids(self) -> Option<Vec<RowIdPair>> {
+        match self {
+            JoinResult::RowIds(pairs) => Some(pairs.into_iter().flatten().collect()),
+            JoinResult::Rows(_) | JoinResult::Count(_) | JoinResult::Checksum(_) => None
+        }
+    }
+}
+
+pub trait JoinAlgorithm: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult;
+}
+
+pub struct NestedLoopJoin;
+
+impl JoinAlgorithm for NestedLoopJoin {
+    fn name(&self) -> &'static str { "nested_loop" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(vec![join::nested_loop_join(&left, &right)]),
+            OutputMode::Count => {
+                let mut sink = CountSink::default();
+                join::nested_loop_join_into(&left, &right, &mut sink);
+                JoinResult::from_counts(vec![sink])
+            }
+            OutputMode::Checksum => {
+                let mut sink = ChecksumSink::default();
+                join::nested_loop_join_into(&left, &right, &mut sink);
+                JoinResult::from_checksums(vec![sink])
+            }
+            OutputMode::RowIds => {
+                let mut sink = RowIdSink(Vec::new());
+                join::nested_loop_join_into(&left, &right, &mut sink);
+                JoinResult::from_row_ids(vec![sink])
+            }
+        }
+    }
+}
+
+pub struct SortMergeJoin;
+
+impl JoinAlgorithm for SortMergeJoin {
+    fn name(&self) -> &'static str { "sort_merge" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(vec![join::basic_sort_merge_join(left, right)]),
+            OutputMode::Count => {
+                let mut sink = CountSink::default();
+                join::basic_sort_merge_join_into(left, right, &mut sink);
+                JoinResult::from_counts(vec![sink])
+            }
+            OutputMode::Checksum => {
+                let mut sink = ChecksumSink::default();
+                join::basic_sort_merge_join_into(left, right, &mut sink);
+                JoinResult::from_checksums(vec![sink])
+            }
+            OutputMode::RowIds => {
+                let mut sink = RowIdSink(Vec::new());
+                join::basic_sort_merge_join_into(left, right, &mut sink);
+                JoinResult::from_row_ids(vec![sink])
+            }
+        }
+    }
+}
+
+pub struct BasicMpsm;
+
+impl JoinAlgorithm for BasicMpsm {
+    fn name(&self) -> &'static str { "basic_mpsm" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(join::basic_mpsm_into(left, right, config.thread_count, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(join::basic_mpsm_into(left, right, config.thread_count, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(join::basic_mpsm_into(left, right, config.thread_count, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(join::basic_mpsm_into(left, right, config.thread_count, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+pub struct PartitionedMpsm;
+
+impl JoinAlgorithm for PartitionedMpsm {
+    fn name(&self) -> &'static str { "partitioned_mpsm" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        // The radix partitioning needs a power of two number of partitions,
+        // and at least two of them.
+        let thread_count = partition_count(config.thread_count);
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(join::partitioned_mpsm_into(left, right, thread_count, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(join::partitioned_mpsm_into(left, right, thread_count, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(join::partitioned_mpsm_into(left, right, thread_count, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(join::partitioned_mpsm_into(left, right, thread_count, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+pub struct RangePartitionedMpsm;
+
+impl JoinAlgorithm for RangePartitionedMpsm {
+    fn name(&self) -> &'static str { "range_partitioned_mpsm" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(join::range_partitioned_mpsm_into(left, right, config.thread_count, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(join::range_partitioned_mpsm_into(left, right, config.thread_count, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(join::range_partitioned_mpsm_into(left, right, config.thread_count, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(join::range_partitioned_mpsm_into(left, right, config.thread_count, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+pub struct MwaySortMerge;
+
+impl JoinAlgorithm for MwaySortMerge {
+    fn name(&self) -> &'static str { "mway_sort_merge" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        // Both inputs are radix partitioned, see PartitionedMpsm
+        let thread_count = partition_count(config.thread_count);
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(join::mway_sort_merge_join_into(left, right, thread_count, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(join::mway_sort_merge_join_into(left, right, thread_count, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(join::mway_sort_merge_join_into(left, right, thread_count, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(join::mway_sort_merge_join_into(left, right, thread_count, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+// Uses `radix` when given, otherwise sizes the partitions of the smaller
+// relation to fit in cache.
+#[derive(Default)]
+pub struct RadixHashJoin {
+    pub radix: Option<RadixConfig>
+}
+
+impl JoinAlgorithm for RadixHashJoin {
+    fn name(&self) -> &'static str { "radix_hash" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        let build_size = left.len().min(right.len());
+        let radix = self.radix.unwrap_or_else(||
+            RadixConfig::for_build_size(build_size, hash_join::CACHE_PARTITION_TUPLES));
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(hash_join::radix_hash_join_into(left, right, config.thread_count, &radix, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(hash_join::radix_hash_join_into(left, right, config.thread_count, &radix, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(hash_join::radix_hash_join_into(left, right, config.thread_count, &radix, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(hash_join::radix_hash_join_into(left, right, config.thread_count, &radix, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+pub struct NoPartitioningJoin;
+
+impl JoinAlgorithm for NoPartitioningJoin {
+    fn name(&self) -> &'static str { "no_partitioning" }
+
+    fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+        match config.output_mode {
+            OutputMode::Materialize => JoinResult::Rows(hash_join::no_partitioning_join_into(left, right, config.thread_count, Vec::new)),
+            OutputMode::Count => JoinResult::from_counts(hash_join::no_partitioning_join_into(left, right, config.thread_count, CountSink::default)),
+            OutputMode::Checksum => JoinResult::from_checksums(hash_join::no_partitioning_join_into(left, right, config.thread_count, ChecksumSink::default)),
+            OutputMode::RowIds => JoinResult::from_row_ids(hash_join::no_partitioning_join_into(left, right, config.thread_count, || RowIdSink(Vec::new())))
+        }
+    }
+}
+
+// Rounds a thread count down to the nearest power of two that is at least 2.
+pub fn partition_count(thread_count: usize) -> usize {
+    if thread_count < 2 { 2 } else { 1 << thread_count.ilog2() }
+}
+
+pub struct Registry {
+    algorithms: Vec<Box<dyn JoinAlgorithm>>
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {algorithms: Vec::new()}
+    }
+
+    pub fn with_defaults() -> Registry {
+        let mut registry = Registry::new();
+        registry.register(Box::new(NestedLoopJoin));
+        registry.register(Box::new(SortMergeJoin));
+        registry.register(Box::new(BasicMpsm));
+        registry.register(Box::new(PartitionedMpsm));
+        registry.register(Box::new(RangePartitionedMpsm));
+        registry.register(Box::new(MwaySortMerge));
+        registry.register(Box::new(RadixHashJoin::default()));
+        registry.register(Box::new(NoPartitioningJoin));
+        registry
+    }
+
+    // Registering an algorithm under an existing name replaces the old one.
+    pub fn register(&mut self, algorithm: Box<dyn JoinAlgorithm>) {
+        self.algorithms.retain(|a| a.name() != algorithm.name());
+        self.algorithms.push(algorithm);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn JoinAlgorithm> {
+        self.algorithms.iter()
+            .find(|a| a.name() == name)
+            .map(|a| a.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.algorithms.iter().map(|a| a.name()).collect()
+    }
+
+    pub fn algorithms(&self) -> impl Iterator<Item = &dyn JoinAlgorithm> {
+        self.algorithms.iter().map(|a| a.as_ref())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::with_defaults()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn registry_lookup() {
+        let registry = Registry::with_defaults();
+        assert_eq!(registry.names(), vec!["nested_loop", "sort_merge", "basic_mpsm", "partitioned_mpsm", "range_partitioned_mpsm", "mway_sort_merge", "radix_hash", "no_partitioning"]);
+        assert_eq!(registry.get("basic_mpsm").map(|a| a.name()), Some("basic_mpsm"));
+        assert!(registry.get("no_such_join").is_none());
+    }
+
+    #[test]
+    fn registry_replaces_by_name() {
+        let mut registry = Registry::with_defaults();
+        registry.register(Box::new(SortMergeJoin));
+        assert_eq!(registry.names().len(), 8);
+    }
+
+    #[test]
+    fn partition_count_test() {
+        assert_eq!(partition_count(1), 2);
+        assert_eq!(partition_count(2), 2);
+        assert_eq!(partition_count(6), 4);
+        assert_eq!(partition_count(8), 8);
+    }
+
+    #[test]
+    fn compare_registry_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+
+        let expected = join::nested_loop_join(&lt, &rt);
+        let mut expected_checksum = ChecksumSink::default();
+        join::nested_loop_join_into(&lt, &rt, &mut expected_checksum);
+
+        let expected_row_ids: Vec<RowIdPair> = expected.iter().map(|j| RowIdPair::from(*j)).collect();
+
+        let registry = Registry::with_defaults();
+        for mode in [OutputMode::Materialize, OutputMode::Count, OutputMode::Checksum, OutputMode::RowIds] {
+            let config = JoinConfig::new(3, mode);
+            for algorithm in registry.algorithms() {
+                let result = algorithm.join(lt.clone(), rt.clone(), &config);
+                assert_eq!(result.count(), expected.len(), "{}", algorithm.name());
+                match result {
+                    JoinResult::Rows(_) => {
+                        let rows = result.into_rows().unwrap();
+                        assert!(infrastructure::table_eq(&expected, &rows), "{}", algorithm.name());
+                    }
+                    JoinResult::Checksum(c) => assert_eq!(c, expected_checksum, "{}", algorithm.name()),
+                    JoinResult::RowIds(_) => {
+                        let pairs = result.into_row_ids().unwrap();
+                        assert!(infrastructure::table_eq(&expected_row_ids, &pairs), "{}", algorithm.name());
+                    }
+                    JoinResult::Count(_) => {}
+                }
+            }
+        }
+    }
+}
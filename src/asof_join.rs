@@ -0,0 +1,160 @@
+use std::thread;
+
+use crate::{parallel, search, tuples::{Joined, Tuple}};
+
+// As-of join: every left tuple is paired with the right tuple that has the
+// greatest key <= the left key. With a tolerance, the right key must also be
+// within `tolerance` of the left key. Left tuples without such a right tuple
+// are dropped. When several right tuples share the greatest key, the one that
+// comes last in the right input wins. Output rows carry the left key.
+
+#[inline]
+fn within(left_key: u64, right_key: u64, tolerance: Option<u64>) -> bool {
+    tolerance.is_none_or(|tol| left_key - right_key <= tol)
+}
+
+pub fn nested_loop_asof_join(left: &[Tuple], right: &[Tuple], tolerance: Option<u64>) -> Vec<Joined> {
+    let mut output = Vec::new();
+
+    for lt in left {
+        let mut best: Option<&Tuple> = None;
+        for rt in right {
+            if rt.key <= lt.key && best.is_none_or(|b| rt.key >= b.key) {
+                best = Some(rt);
+            }
+        }
+        if let Some(rt) = best.filter(|rt| within(lt.key, rt.key, tolerance)) {
+            output.push(Joined::new(lt.key, lt.payload, rt.payload));
+        }
+    }
+
+    output
+}
+
+// Index of the first tuple in right[from..] with a key > target. The tuple just
+// before it is the as-of candidate, and the next, larger, target resumes the
+// search from it.
+#[inline]
+fn upper_bound_from(right: &[Tuple], from: usize, target: u64) -> usize {
+    match target.checked_add(1) {
+        Some(past) => search::lb_binary_search_by_key(&past, &right[from..], |t| &t.key)
+            .map_or(right.len(), |i| from + i),
+        None => right.len()
+    }
+}
+
+// As-of join of two inputs sorted by key. The right input must be sorted with
+// a stable sort for ties to resolve to the latest right tuple.
+pub fn asof_join_sorted(left: &[Tuple], right: &[Tuple], tolerance: Option<u64>, output: &mut Vec<Joined>) {
+    let mut end = 0;
+
+    for lt in left {
+        end = upper_bound_from(right, end, lt.key);
+        if end == 0 {
+            continue;
+        }
+        let rt = &right[end - 1];
+        if within(lt.key, rt.key, tolerance) {
+            output.push(Joined::new(lt.key, lt.payload, rt.payload));
+        }
+    }
+}
+
+pub fn sort_asof_join(mut left: Vec<Tuple>, mut right: Vec<Tuple>, tolerance: Option<u64>) -> Vec<Joined> {
+    left.sort_by_key(|t| t.key);
+    right.sort_by_key(|t| t.key);
+
+    let mut output = Vec::new();
+    asof_join_sorted(&left, &right, tolerance, &mut output);
+    output
+}
+
+// MPSM style as-of join. The right (public) input is sorted into runs in
+// parallel. Every worker sorts a chunk of the left (private) input, finds the
+// best candidate of each left tuple in every public run and keeps the one with
+// the greatest key. Runs are visited in input order, so a later run wins ties.
+pub fn mpsm_asof_join(mut left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, tolerance: Option<u64>) -> Vec<Vec<Joined>> {
+    assert!(thread_count > 0);
+
+    parallel::sort_runs_parallel(&mut right, thread_count);
+
+    let public: &[Tuple] = &right;
+    let private_chunk_size = left.len().div_ceil(thread_count).max(1);
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for private_chunk in left.chunks_mut(private_chunk_size) {
+            handles.push(s.spawn(move || {
+                private_chunk.sort_by_key(|t| t.key);
+
+                let mut best: Vec<Option<Tuple>> = vec![None; private_chunk.len()];
+                for run in public.chunks(public_chunk_size) {
+                    let mut end = 0;
+                    for (lt, b) in private_chunk.iter().zip(best.iter_mut()) {
+                        end = upper_bound_from(run, end, lt.key);
+                        if end > 0 && b.is_none_or(|b| run[end - 1].key >= b.key) {
+                            *b = Some(run[end - 1]);
+                        }
+                    }
+                }
+
+                private_chunk.iter().zip(best)
+                    .filter_map(|(lt, b)| b.map(|rt| (lt, rt)))
+                    .filter(|(lt, rt)| within(lt.key, rt.key, tolerance))
+                    .map(|(lt, rt)| Joined::new(lt.key, lt.payload, rt.payload))
+                    .collect::<Vec<Joined>>()
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn asof_join_test() {
+        let left = vec![Tuple::new(5, 1), Tuple::new(9, 2), Tuple::new(1, 3), Tuple::new(20, 4)];
+        let right = vec![Tuple::new(4, 10), Tuple::new(2, 11), Tuple::new(4, 12), Tuple::new(9, 13)];
+
+        let output = sort_asof_join(left.clone(), right.clone(), None);
+        assert_eq!(output, vec![
+            Joined::new(5, 1, 12),
+            Joined::new(9, 2, 13),
+            Joined::new(20, 4, 13)
+        ]);
+
+        let output = sort_asof_join(left, right, Some(3));
+        assert_eq!(output, vec![Joined::new(5, 1, 12), Joined::new(9, 2, 13)]);
+    }
+
+    #[test]
+    fn compare_asof_joins_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        // Narrow key domains give plenty of exact hits and duplicate keys
+        let lt: Vec<Tuple> = (0..1500).map(|_| Tuple::new(rng.random_range(0..5000), rng.random())).collect();
+        let rt: Vec<Tuple> = (0..1000).map(|_| Tuple::new(rng.random_range(0..5000), rng.random())).collect();
+
+        for tolerance in [None, Some(0), Some(4)] {
+            let nl_output = nested_loop_asof_join(&lt, &rt, tolerance);
+
+            let sm_output = sort_asof_join(lt.clone(), rt.clone(), tolerance);
+            assert!(infrastructure::table_eq(&nl_output, &sm_output), "{tolerance:?}");
+
+            let mpsm_output = mpsm_asof_join(lt.clone(), rt.clone(), 4, tolerance)
+                .into_iter().flatten().collect::<Vec<Joined>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "{tolerance:?}");
+        }
+    }
+}
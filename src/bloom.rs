@@ -0,0 +1,218 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, thread, time::Duration};
+
+use crate::{affinity, context::{self, JoinContext, JoinError, CHECK_INTERVAL}, tuples::Tuple};
+
+// Cache line blocked Bloom filter (Putze et al., 2007). All k bits of a key
+// live in one 512 bit block, so a probe touches a single cache line.
+
+const WORDS_PER_BLOCK: usize = 8;
+const BLOCK_BITS: usize = WORDS_PER_BLOCK * 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BloomConfig {
+    pub bits_per_key: usize
+}
+
+impl BloomConfig {
+    pub fn new(bits_per_key: usize) -> BloomConfig {
+        assert!(bits_per_key > 0);
+        BloomConfig {bits_per_key}
+    }
+
+    // Bits set per key: bits_per_key * ln 2 minimizes the false positive rate.
+    pub fn hash_count(&self) -> u32 {
+        ((self.bits_per_key as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, 14)
+    }
+}
+
+impl Default for BloomConfig {
+    // About 1% false positives
+    fn default() -> BloomConfig {
+        BloomConfig::new(10)
+    }
+}
+
+// Finalizer of murmur3. Keys that differ in any bit land in unrelated blocks.
+#[inline]
+fn hash(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xC4CE_B9FE_1A85_EC53);
+    x ^ (x >> 33)
+}
+
+pub struct BlockedBloomFilter {
+    words: Vec<AtomicU64>,
+    block_count: usize,
+    hash_count: u32
+}
+
+impl BlockedBloomFilter {
+    pub fn new(key_count: usize, config: &BloomConfig) -> BlockedBloomFilter {
+        let block_count = (key_count * config.bits_per_key).div_ceil(BLOCK_BITS).max(1);
+        BlockedBloomFilter {
+            words: (0..block_count * WORDS_PER_BLOCK).map(|_| AtomicU64::new(0)).collect(),
+            block_count,
+            hash_count: config.hash_count()
+        }
+    }
+
+    // Builds the filter over the keys of a table with thread_count workers.
+    pub fn build(table: &[Tuple], thread_count: usize, config: &BloomConfig) -> BlockedBloomFilter {
+        context::unbounded(|ctx| BlockedBloomFilter::build_ctx(table, thread_count, config, ctx))
+    }
+
+    // Inserts the keys in blocks of CHECK_INTERVAL, checking ctx between them.
+    pub fn build_ctx(table: &[Tuple], thread_count: usize, config: &BloomConfig, ctx: &JoinContext) -> Result<BlockedBloomFilter, JoinError> {
+        assert!(thread_count > 0);
+
+        let filter = BlockedBloomFilter::new(table.len(), config);
+        let chunk_size = table.len().div_ceil(thread_count).max(1);
+        thread::scope(|s| {
+            let handles: Vec<_> = table.chunks(chunk_size).enumerate()
+                .map(|(worker, chunk)| {
+                    let filter = &filter;
+                    affinity::spawn(s, worker, move || {
+                        for block in chunk.chunks(CHECK_INTERVAL) {
+                            ctx.check()?;
+                            block.iter().for_each(|t| filter.insert(t.key));
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })?;
+        Ok(filter)
+    }
+
+    pub fn size_bits(&self) -> usize {
+        self.block_count * BLOCK_BITS
+    }
+
+    // One hash picks the block. The bit positions inside the block take 9 bits
+    // each from a second hash, and from a third one past 7 positions.
+    #[inline]
+    fn positions(&self, key: u64) -> (usize, impl Iterator<Item = usize>) {
+        let h = hash(key);
+        let block = ((h as u128 * self.block_count as u128) >> 64) as usize;
+        let g = hash(h ^ 0x9E37_79B9_7F4A_7C15);
+        let g2 = hash(g);
+        let bits = (0..self.hash_count).map(move |i| {
+            let source = if i < 7 { g >> (9 * i) } else { g2 >> (9 * (i - 7)) };
+            (source as usize) % BLOCK_BITS
+        });
+        (block * WORDS_PER_BLOCK, bits)
+    }
+
+    pub fn insert(&self, key: u64) {
+        let (base, bits) = self.positions(key);
+        for bit in bits {
+            self.words[base + bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, key: u64) -> bool {
+        let (base, mut bits) = self.positions(key);
+        bits.all(|bit| self.words[base + bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    // Keeps the tuples whose key may be in the filter, with thread_count workers.
+    pub fn filter(&self, table: Vec<Tuple>, thread_count: usize) -> Vec<Tuple> {
+        context::unbounded(|ctx| self.filter_ctx(table, thread_count, ctx))
+    }
+
+    // Probes the keys in blocks of CHECK_INTERVAL, checking ctx between them.
+    pub fn filter_ctx(&self, table: Vec<Tuple>, thread_count: usize, ctx: &JoinContext) -> Result<Vec<Tuple>, JoinError> {
+        assert!(thread_count > 0);
+
+        let chunk_size = table.len().div_ceil(thread_count).max(1);
+        thread::scope(|s| {
+            let handles: Vec<_> = table.chunks(chunk_size).enumerate()
+                .map(|(worker, chunk)| affinity::spawn(s, worker, move || {
+                    let mut passed: Vec<Tuple> = Vec::new();
+                    for block in chunk.chunks(CHECK_INTERVAL) {
+                        ctx.check()?;
+                        passed.extend(block.iter().filter(|t| self.contains(t.key)));
+                    }
+                    Ok(passed)
+                }))
+                .collect();
+            let mut output = Vec::new();
+            for h in handles {
+                output.extend(h.join().unwrap()?);
+            }
+            Ok(output)
+        })
+    }
+}
+
+// What the Bloom filter of a join did.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BloomStats {
+    pub bits_per_key: usize,
+    pub filter_bits: usize,
+    // Tuples of the smaller relation the filter was built over
+    pub build_tuples: usize,
+    // Tuples of the larger relation that were probed and that passed
+    pub probed: usize,
+    pub passed: usize,
+    // Passed tuples without a join partner
+    pub false_positives: usize,
+    pub build_time: Duration,
+    pub probe_time: Duration,
+    // Estimated time the sort and scatter phases would have spent on the
+    // dropped tuples, minus build_time and probe_time. Zero when the filter
+    // cost more than it saved.
+    pub time_saved: Duration
+}
+
+impl BloomStats {
+    pub fn dropped(&self) -> usize {
+        self.probed - self.passed
+    }
+
+    // Share of the probed tuples without a join partner that still passed.
+    pub fn false_positive_rate(&self) -> f64 {
+        let negatives = self.probed - (self.passed - self.false_positives);
+        if negatives == 0 { 0.0 } else { self.false_positives as f64 / negatives as f64 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let table = infrastructure::gen_table(20000, &mut rng);
+
+        let filter = BlockedBloomFilter::build(&table, 4, &BloomConfig::default());
+        assert!(table.iter().all(|t| filter.contains(t.key)));
+        assert_eq!(filter.filter(table.clone(), 3).len(), table.len());
+    }
+
+    #[test]
+    fn false_positive_rate_follows_bits_per_key() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let table = infrastructure::gen_table(20000, &mut rng);
+        let others = infrastructure::gen_table(100000, &mut rng);
+
+        let rate = |bits_per_key| {
+            let filter = BlockedBloomFilter::build(&table, 4, &BloomConfig::new(bits_per_key));
+            others.iter().filter(|t| filter.contains(t.key)).count() as f64 / others.len() as f64
+        };
+        let (r4, r10, r20) = (rate(4), rate(10), rate(20));
+        // Blocking costs a little over the textbook 14.7%, 0.8% and 0.01%
+        assert!(r4 > 0.1 && r4 < 0.25, "{r4}");
+        assert!(r10 < 0.02, "{r10}");
+        assert!(r20 < r10);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{fmt, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use crate::sink::JoinSink;
+
+// Cooperative control of a long running join. The workers of every phase
+// check the context between blocks of CHECK_INTERVAL tuples and stop once the
+// join was cancelled or ran past its deadline; the join then returns the
+// JoinError instead of its output. Long sorts are split into sub-runs of
+// CHECK_INTERVAL tuples for this, see parallel::sort_chunk_ctx. The MPSM
+// joins, including their outer, filter, Bloom filtered and skew aware forms,
+// the m-way sort-merge join, the P-MPSM group-join, the Bloom filter and the
+// phases in parallel.rs take a context in their _ctx variants; the variants
+// without one run under an unbounded context.
+
+// Number of tuples a worker handles between two checks of its context.
+pub const CHECK_INTERVAL: usize = 1 << 16;
+
+// Shared flag that cancels every join whose context holds a clone of it.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    Cancelled,
+    DeadlineExceeded
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "join was cancelled"),
+            JoinError::DeadlineExceeded => write!(f, "join ran past its deadline")
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+// Work done so far, summed over all workers and phases.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub tuples_sorted: u64,
+    pub tuples_scattered: u64,
+    pub matches_emitted: u64
+}
+
+type ProgressCallback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+#[derive(Default)]
+pub struct JoinContext {
+    cancellation: Option<CancellationToken>,
+    deadline: Option<Instant>,
+    // Called by the workers, possibly at the same time, whenever they report
+    // progress: after every sorted run and every block scattered or merged.
+    on_progress: Option<ProgressCallback>,
+    tuples_sorted: AtomicU64,
+    tuples_scattered: AtomicU64,
+    matches_emitted: AtomicU64
+}
+
+impl JoinContext {
+    // A context that never stops the join.
+    pub fn new() -> JoinContext {
+        JoinContext::default()
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> JoinContext {
+        self.cancellation = Some(token);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> JoinContext {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> JoinContext {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn with_progress<F: Fn(&Progress) + Send + Sync + 'static>(mut self, on_progress: F) -> JoinContext {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    #[inline]
+    pub fn check(&self) -> Result<(), JoinError> {
+        if self.cancellation.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Err(JoinError::Cancelled);
+        }
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(JoinError::DeadlineExceeded);
+        }
+        Ok(())
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            tuples_sorted: self.tuples_sorted.load(Ordering::Relaxed),
+            tuples_scattered: self.tuples_scattered.load(Ordering::Relaxed),
+            matches_emitted: self.matches_emitted.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn add_sorted(&self, n: usize) {
+        self.tuples_sorted.fetch_add(n as u64, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub fn add_scattered(&self, n: usize) {
+        self.tuples_scattered.fetch_add(n as u64, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub fn add_matches(&self, n: u64) {
+        self.matches_emitted.fetch_add(n, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&self.progress());
+        }
+    }
+}
+
+impl fmt::Debug for JoinContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinContext")
+            .field("cancellation", &self.cancellation)
+            .field("deadline", &self.deadline)
+            .field("progress", &self.progress())
+            .finish()
+    }
+}
+
+// Runs a phase under a context without cancellation or deadline, which never
+// fails. Backs the variants of the joins that take no context.
+pub fn unbounded<R>(f: impl FnOnce(&JoinContext) -> Result<R, JoinError>) -> R {
+    f(&JoinContext::new()).expect("a join without cancellation or deadline cannot fail")
+}
+
+// Forwards rows to a sink and counts them, so that a merge can report the
+// matches it emitted.
+pub struct CountingSink<'a, S> {
+    inner: &'a mut S,
+    pub count: u64
+}
+
+impl<'a, S> CountingSink<'a, S> {
+    pub fn new(inner: &'a mut S) -> CountingSink<'a, S> {
+        CountingSink {inner, count: 0}
+    }
+}
+
+impl<T, S: JoinSink<T>> JoinSink<T> for CountingSink<'_, S> {
+    #[inline]
+    fn push(&mut self, row: T) {
+        self.count += 1;
+        self.inner.push(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{bloom::{BlockedBloomFilter, BloomConfig}, group_join::{self, Count}, infrastructure, join::{self, JoinFilter, JoinType}, parallel, sink::CountSink, tuples::Joined};
+
+    use super::*;
+
+    #[test]
+    fn cancelled_and_expired_joins_fail() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let ctx = JoinContext::new().with_cancellation(token);
+        assert_eq!(join::partitioned_mpsm_ctx(lt.clone(), rt.clone(), 4, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+
+        let ctx = JoinContext::new().with_deadline(Instant::now());
+        assert_eq!(join::basic_mpsm_ctx(lt.clone(), rt.clone(), 4, &ctx, Vec::new).unwrap_err(), JoinError::DeadlineExceeded);
+
+        let ctx = JoinContext::new().with_timeout(Duration::from_secs(3600));
+        let output = join::range_partitioned_mpsm_ctx(lt.clone(), rt.clone(), 4, &ctx, Vec::new).unwrap()
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&join::nested_loop_join(&lt, &rt), &output));
+    }
+
+    #[test]
+    fn progress_is_reported() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(300000, 0.7, &mut rng);
+        let (l_len, r_len) = (lt.len() as u64, rt.len() as u64);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&reports);
+        let ctx = JoinContext::new().with_progress(move |p| seen.lock().unwrap().push(*p));
+
+        let count = join::partitioned_mpsm_ctx(lt, rt, 4, &ctx, CountSink::default).unwrap()
+            .iter().map(|s| s.count).sum::<u64>();
+        assert_eq!(ctx.progress(), Progress {tuples_sorted: l_len + r_len, tuples_scattered: l_len, matches_emitted: count});
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 4);
+        assert!(reports.iter().any(|p| p.matches_emitted > 0 && p.matches_emitted < count));
+    }
+
+    #[test]
+    fn cancel_from_progress_callback() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(300000, 0.7, &mut rng);
+
+        // Cancel as soon as the first run is sorted
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let ctx = JoinContext::new()
+            .with_cancellation(token)
+            .with_progress(move |_| canceller.cancel());
+
+        assert_eq!(join::partitioned_mpsm_ctx(lt, rt, 4, &ctx, CountSink::default).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(ctx.progress().matches_emitted, 0);
+    }
+
+    #[test]
+    fn every_join_variant_can_be_cancelled() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let ctx = JoinContext::new().with_cancellation(token);
+        assert_eq!(join::skew_aware_mpsm_ctx(lt.clone(), rt.clone(), 4, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::mway_sort_merge_join_ctx(lt.clone(), rt.clone(), 4, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::bloom_partitioned_mpsm_ctx(lt.clone(), rt.clone(), 4, Some(BloomConfig::default()), &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::partitioned_mpsm_filter_ctx(lt.clone(), rt.clone(), 4, JoinFilter::Semi, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::basic_mpsm_outer_ctx(lt.clone(), rt.clone(), 4, JoinType::FullOuter, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::partitioned_mpsm_outer_ctx(lt.clone(), rt.clone(), 4, JoinType::FullOuter, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(join::range_partitioned_mpsm_outer_ctx(lt.clone(), rt.clone(), 4, JoinType::LeftOuter, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(group_join::partitioned_mpsm_group_join_ctx(lt, rt, &Count, 4, &ctx, Vec::new).unwrap_err(), JoinError::Cancelled);
+
+        // Cancel once the outer join has emitted its first rows
+        let (lt, rt) = infrastructure::gen_tables(300000, 0.7, &mut rng);
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let ctx = JoinContext::new()
+            .with_cancellation(token)
+            .with_progress(move |p| if p.matches_emitted > 0 { canceller.cancel() });
+        assert_eq!(join::partitioned_mpsm_outer_ctx(lt, rt, 4, JoinType::FullOuter, &ctx, CountSink::default).unwrap_err(), JoinError::Cancelled);
+        assert!(ctx.progress().matches_emitted > 0);
+    }
+
+    #[test]
+    fn long_sorts_stop_between_sub_runs() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let mut table = infrastructure::gen_table(10 * CHECK_INTERVAL + 7, &mut rng);
+
+        // A single run, cancelled once its first sub-run is sorted
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let ctx = JoinContext::new()
+            .with_cancellation(token)
+            .with_progress(move |_| canceller.cancel());
+        assert_eq!(parallel::sort_runs_parallel_ctx(&mut table, 1, &ctx).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(ctx.progress().tuples_sorted, CHECK_INTERVAL as u64);
+
+        // Sorting in sub-runs keeps the order of equal keys
+        let mut expected = table.clone();
+        expected.sort_by_key(|t| t.key);
+        let ctx = JoinContext::new();
+        parallel::sort_chunk_ctx(&mut table, &ctx).unwrap();
+        assert_eq!(table, expected);
+        assert_eq!(ctx.progress().tuples_sorted, table.len() as u64);
+    }
+
+    #[test]
+    fn every_phase_can_be_cancelled() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let table = infrastructure::gen_table(5000, &mut rng);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let ctx = JoinContext::new().with_cancellation(token);
+        assert_eq!(parallel::chunk_histograms_heavy_hitters_ctx(&table, 4, 16, &ctx).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(parallel::radix_partition_ctx(&table, 60, 4, &ctx).unwrap_err(), JoinError::Cancelled);
+        assert_eq!(BlockedBloomFilter::build_ctx(&table, 4, &BloomConfig::default(), &ctx).err(), Some(JoinError::Cancelled));
+        let filter = BlockedBloomFilter::build(&table, 4, &BloomConfig::default());
+        assert_eq!(filter.filter_ctx(table.clone(), 4, &ctx).unwrap_err(), JoinError::Cancelled);
+    }
+}
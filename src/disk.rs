@@ -0,0 +1,422 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Read, Seek, SeekFrom, Write}, ops::Range, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, thread};
+
+use crate::{histograms, join, parallel, sink::JoinSink, tuples::{Joined, Tuple}};
+
+// Disk-enabled MPSM (D-MPSM). The sorted public runs are written to files and
+// paged back in while the private partitions are merged against them, so the
+// public input only has to fit on disk. The private input stays in memory.
+//
+// Run file format, all integers little endian:
+//   8 bytes  RUN_MAGIC
+//   8 bytes  number of tuples n
+//   n * 16   tuples as (key, payload), sorted by key
+
+pub const RUN_MAGIC: [u8; 8] = *b"MPSMRUN1";
+const HEADER_BYTES: u64 = 16;
+const TUPLE_BYTES: usize = 16;
+
+// Where D-MPSM keeps its run files and how much memory the paged in public
+// data may take across all workers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskConfig {
+    pub dir: PathBuf,
+    pub memory_budget: usize
+}
+
+impl DiskConfig {
+    pub fn new(dir: impl Into<PathBuf>, memory_budget: usize) -> DiskConfig {
+        DiskConfig {dir: dir.into(), memory_budget}
+    }
+
+    // Tuples per page such that every worker can hold one page, both as raw
+    // bytes and decoded, within the memory budget.
+    pub fn page_tuples(&self, thread_count: usize) -> usize {
+        assert!(thread_count > 0);
+        (self.memory_budget / (thread_count * 2 * TUPLE_BYTES)).max(1)
+    }
+}
+
+// A sorted run on disk. The first key of every page stays in memory, so a
+// worker reads only the pages that overlap its key range.
+#[derive(Debug)]
+pub struct RunFile {
+    path: PathBuf,
+    len: usize,
+    page_tuples: usize,
+    fences: Vec<u64>
+}
+
+impl RunFile {
+    pub fn write(path: impl Into<PathBuf>, run: &[Tuple], page_tuples: usize) -> io::Result<RunFile> {
+        assert!(page_tuples > 0);
+        debug_assert!(run.is_sorted_by_key(|t| t.key));
+
+        let path = path.into();
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(&RUN_MAGIC)?;
+        writer.write_all(&(run.len() as u64).to_le_bytes())?;
+        for t in run {
+            writer.write_all(&t.key.to_le_bytes())?;
+            writer.write_all(&t.payload.to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        let fences = run.chunks(page_tuples).map(|page| page[0].key).collect();
+        Ok(RunFile {path, len: run.len(), page_tuples, fences})
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.fences.len()
+    }
+
+    // Pages that may hold keys in [lo, hi]. A page covers the keys from its
+    // fence up to the next fence, inclusive, since equal keys can straddle a
+    // page boundary.
+    pub fn pages_between(&self, lo: u64, hi: u64) -> Range<usize> {
+        let start = self.fences.partition_point(|f| *f < lo).saturating_sub(1);
+        let end = self.fences.partition_point(|f| *f <= hi);
+        start..end.max(start)
+    }
+}
+
+// Reads the pages of one run file through a buffer of a single page.
+pub struct RunReader<'a> {
+    run: &'a RunFile,
+    file: File,
+    bytes: Vec<u8>,
+    page: Vec<Tuple>
+}
+
+impl<'a> RunReader<'a> {
+    pub fn open(run: &'a RunFile) -> io::Result<RunReader<'a>> {
+        let mut file = File::open(&run.path)?;
+
+        let mut header = [0u8; HEADER_BYTES as usize];
+        file.read_exact(&mut header)?;
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+        if header[..8] != RUN_MAGIC || len != run.len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} is not the expected run file", run.path.display())));
+        }
+
+        Ok(RunReader {
+            run,
+            file,
+            bytes: Vec::with_capacity(run.page_tuples * TUPLE_BYTES),
+            page: Vec::with_capacity(run.page_tuples)
+        })
+    }
+
+    pub fn read_page(&mut self, page: usize) -> io::Result<&[Tuple]> {
+        assert!(page < self.run.page_count());
+
+        let start = page * self.run.page_tuples;
+        let len = self.run.page_tuples.min(self.run.len - start);
+
+        self.bytes.resize(len * TUPLE_BYTES, 0);
+        self.file.seek(SeekFrom::Start(HEADER_BYTES + (start * TUPLE_BYTES) as u64))?;
+        self.file.read_exact(&mut self.bytes)?;
+
+        self.page.clear();
+        for b in self.bytes.chunks_exact(TUPLE_BYTES) {
+            let key = u64::from_le_bytes(b[..8].try_into().unwrap());
+            let payload = u64::from_le_bytes(b[8..].try_into().unwrap());
+            self.page.push(Tuple::new(key, payload));
+        }
+        Ok(&self.page)
+    }
+}
+
+// Numbers the SpilledRuns of this process, so that several of them can share
+// a directory.
+static NEXT_SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The public input of D-MPSM as a set of sorted runs on disk. The input can be
+// spilled in batches that each fit in memory; every batch adds thread_count
+// runs. The run files are removed on drop.
+#[derive(Debug)]
+pub struct SpilledRuns {
+    dir: PathBuf,
+    // Prefix of the run file names, unique among the SpilledRuns of all
+    // processes that use the same directory
+    prefix: String,
+    page_tuples: usize,
+    runs: Vec<RunFile>
+}
+
+impl SpilledRuns {
+    pub fn new(dir: impl Into<PathBuf>, page_tuples: usize) -> io::Result<SpilledRuns> {
+        assert!(page_tuples > 0);
+
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let prefix = format!("mpsm-{}-{}", std::process::id(), NEXT_SPILL_ID.fetch_add(1, Ordering::Relaxed));
+        Ok(SpilledRuns {dir, prefix, page_tuples, runs: Vec::new()})
+    }
+
+    // Phase 1 of D-MPSM: sorts the batch into thread_count runs with
+    // parallel::sort_runs_parallel and writes every run to its own file.
+    pub fn spill(&mut self, mut batch: Vec<Tuple>, thread_count: usize) -> io::Result<()> {
+        assert!(thread_count > 0);
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let chunk_size = batch.len().div_ceil(thread_count).max(1);
+        parallel::sort_runs_parallel(&mut batch, thread_count);
+
+        let first_id = self.runs.len();
+        let paths: Vec<PathBuf> = (0..batch.len().div_ceil(chunk_size))
+            .map(|i| self.dir.join(format!("{}-run-{}.bin", self.prefix, first_id + i)))
+            .collect();
+        let page_tuples = self.page_tuples;
+        let written: io::Result<Vec<RunFile>> = thread::scope(|s| {
+            let handles: Vec<_> = batch.chunks(chunk_size).zip(&paths)
+                .map(|(run, path)| s.spawn(move || RunFile::write(path, run, page_tuples)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        match written {
+            Ok(runs) => {
+                self.runs.extend(runs);
+                Ok(())
+            }
+            Err(e) => {
+                // Only the runs in self.runs are removed on drop, so remove
+                // the whole batch here, including a partly written file.
+                for path in &paths {
+                    let _ = fs::remove_file(path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn runs(&self) -> &[RunFile] {
+        &self.runs
+    }
+
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|r| r.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // parts - 1 splitters from the page fences. Every page holds the same
+    // number of tuples, so the fences are an equi-depth sample of the runs.
+    pub fn splitters(&self, parts: usize) -> Vec<u64> {
+        let fence_runs: Vec<Vec<Tuple>> = self.runs.iter()
+            .map(|r| r.fences.iter().map(|k| Tuple::new(*k, 0)).collect())
+            .collect();
+        let fence_runs: Vec<&[Tuple]> = fence_runs.iter().map(|r| r.as_slice()).collect();
+        let samples_per_run = fence_runs.iter().map(|r| r.len()).max().unwrap_or(0).max(1);
+        histograms::equi_depth_splitters(&fence_runs, parts, samples_per_run)
+    }
+}
+
+impl Drop for SpilledRuns {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(&run.path);
+        }
+    }
+}
+
+pub fn disk_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, config: &DiskConfig) -> io::Result<Vec<Vec<Joined>>> {
+    disk_mpsm_into(left, right, thread_count, config, Vec::new)
+}
+
+// D-MPSM on a public input that fits in memory once: spills it to
+// config.dir, frees it and joins against the run files.
+pub fn disk_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, config: &DiskConfig, make_sink: F) -> io::Result<Vec<S>>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    let mut runs = SpilledRuns::new(&config.dir, config.page_tuples(thread_count))?;
+    runs.spill(right, thread_count)?;
+    join_spilled_runs_into(left, &runs, thread_count, make_sink)
+}
+
+// Phases 2 to 4 of D-MPSM. The private input is range partitioned on
+// splitters from the page fences, and every worker sorts its partition and
+// pages in, one page at a time, the part of each run that overlaps it.
+pub fn join_spilled_runs_into<S, F>(left: Vec<Tuple>, runs: &SpilledRuns, thread_count: usize, make_sink: F) -> io::Result<Vec<S>>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    // Phase 2
+    let splitters = runs.splitters(thread_count);
+    let mut private_chunks = join::range_partition_private_on(&left, &splitters, thread_count);
+    drop(left);
+
+    let make_sink = &make_sink;
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for private_chunk in &mut private_chunks {
+            handles.push(s.spawn(move || {
+                // Phase 3
+                private_chunk.sort_by_key(|t| t.key);
+
+                // Phase 4
+                let mut output = make_sink();
+                let (Some(first), Some(last)) = (private_chunk.first(), private_chunk.last()) else {
+                    return Ok(output);
+                };
+                for run in runs.runs() {
+                    let mut reader = RunReader::open(run)?;
+                    for page in run.pages_between(first.key, last.key) {
+                        let page = reader.read_page(page)?;
+                        let private = join::key_window(private_chunk, page[0].key, page[page.len() - 1].key);
+                        join::merge_join_sorted(private, page, &mut output);
+                    }
+                }
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mpsm-disk-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn run_file_roundtrip() {
+        let dir = test_dir("roundtrip");
+        fs::create_dir_all(&dir).unwrap();
+
+        let run: Vec<Tuple> = (0..10).map(|k| Tuple::new(k / 2, k)).collect();
+        let file = RunFile::write(dir.join("run.bin"), &run, 4).unwrap();
+        assert_eq!(file.page_count(), 3);
+        assert_eq!(fs::metadata(file.path()).unwrap().len(), HEADER_BYTES + 10 * TUPLE_BYTES as u64);
+
+        let mut reader = RunReader::open(&file).unwrap();
+        assert_eq!(reader.read_page(2).unwrap(), &run[8..]);
+        assert_eq!(reader.read_page(0).unwrap(), &run[..4]);
+
+        // Key 1 sits at the end of page 0, key 2 straddles pages 0 and 1
+        assert_eq!(file.pages_between(1, 1), 0..1);
+        assert_eq!(file.pages_between(2, 2), 0..2);
+        assert_eq!(file.pages_between(5, 9), 2..3);
+        assert_eq!(file.pages_between(0, 0), 0..1);
+
+        fs::write(file.path(), b"not a run file at all").unwrap();
+        assert_eq!(RunReader::open(&file).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compare_disk_mpsm_nested_loop() {
+        let dir = test_dir("compare");
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+        let nl_output = join::nested_loop_join(&lt, &rt);
+
+        // A budget of a few pages per worker
+        let config = DiskConfig::new(&dir, 4 * 2 * TUPLE_BYTES * 100);
+        let mpsm_output = disk_mpsm(lt, rt, 4, &config).unwrap()
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &mpsm_output));
+
+        // The run files are gone once the join returns
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disk_mpsm_spilled_batches_with_duplicates() {
+        let dir = test_dir("batches");
+        // Duplicate public keys straddle the page boundaries
+        let lt: Vec<Tuple> = (0..3000).map(|k| Tuple::new(k % 700, k)).collect();
+        let rt: Vec<Tuple> = (0..2000).map(|k| Tuple::new((k * 7) % 500, k)).collect();
+        let nl_output = join::nested_loop_join(&lt, &rt);
+
+        let mut runs = SpilledRuns::new(&dir, 16).unwrap();
+        for batch in rt.chunks(600) {
+            runs.spill(batch.to_vec(), 3).unwrap();
+        }
+        assert_eq!(runs.runs().len(), 12);
+        assert_eq!(runs.len(), rt.len());
+
+        let mpsm_output = join_spilled_runs_into(lt, &runs, 3, Vec::new).unwrap()
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &mpsm_output));
+
+        drop(runs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spilled_runs_share_a_directory() {
+        let dir = test_dir("shared");
+        let rt: Vec<Tuple> = (0..1000).map(|k| Tuple::new(k, k)).collect();
+
+        let mut first = SpilledRuns::new(&dir, 16).unwrap();
+        let mut second = SpilledRuns::new(&dir, 16).unwrap();
+        first.spill(rt.clone(), 2).unwrap();
+        second.spill(rt[..500].to_vec(), 2).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        // Dropping one set of runs leaves the files of the other alone
+        drop(first);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        let output = join_spilled_runs_into(rt, &second, 2, Vec::new).unwrap()
+            .into_iter().flatten().count();
+        assert_eq!(output, 500);
+
+        drop(second);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_spill_removes_its_run_files() {
+        let dir = test_dir("failed_spill");
+        let rt: Vec<Tuple> = (0..1000).map(|k| Tuple::new(k, k)).collect();
+
+        let mut runs = SpilledRuns::new(&dir, 16).unwrap();
+        runs.spill(rt[..100].to_vec(), 2).unwrap();
+        // A directory in the place of the fourth run file fails its write
+        let blocker = dir.join(format!("{}-run-3.bin", runs.prefix));
+        fs::create_dir(&blocker).unwrap();
+
+        assert!(runs.spill(rt, 4).is_err());
+        assert_eq!(runs.runs().len(), 2);
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        let expected: Vec<std::ffi::OsString> = [0, 1, 3].iter().map(|i| format!("{}-run-{i}.bin", runs.prefix).into()).collect();
+        assert_eq!(names, expected);
+
+        fs::remove_dir(&blocker).unwrap();
+        drop(runs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
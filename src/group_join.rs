@@ -0,0 +1,285 @@
+use std::thread;
+
+use crate::{affinity, algorithms::partition_count, context::{self, CountingSink, JoinContext, JoinError, CHECK_INTERVAL}, join, parallel, sink::JoinSink, tuples::{Joined, Tuple}};
+
+// Group-join: a join followed by a grouping on the join key of the left
+// (dimension) input, in one operator. Matches are folded into the aggregate
+// of their key as the merge join finds them, so no joined row is stored.
+
+// Aggregate function over the joined rows of one key. Joined rows carry the
+// dimension payload as left_payload and the fact payload as right_payload.
+pub trait Aggregate: Sync {
+    type State: Send;
+    type Output;
+
+    fn init(&self) -> Self::State;
+    fn update(&self, state: &mut Self::State, row: &Joined);
+    fn finish(&self, state: Self::State) -> Self::Output;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Count;
+
+impl Aggregate for Count {
+    type State = u64;
+    type Output = u64;
+
+    fn init(&self) -> u64 { 0 }
+    #[inline]
+    fn update(&self, state: &mut u64, _row: &Joined) { *state += 1; }
+    fn finish(&self, state: u64) -> u64 { state }
+}
+
+// Sum of the fact payloads. Wide enough that u64 payloads cannot overflow it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sum;
+
+impl Aggregate for Sum {
+    type State = u128;
+    type Output = u128;
+
+    fn init(&self) -> u128 { 0 }
+    #[inline]
+    fn update(&self, state: &mut u128, row: &Joined) { *state += row.right_payload as u128; }
+    fn finish(&self, state: u128) -> u128 { state }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Min;
+
+impl Aggregate for Min {
+    type State = u64;
+    type Output = u64;
+
+    fn init(&self) -> u64 { u64::MAX }
+    #[inline]
+    fn update(&self, state: &mut u64, row: &Joined) { *state = (*state).min(row.right_payload); }
+    fn finish(&self, state: u64) -> u64 { state }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Max;
+
+impl Aggregate for Max {
+    type State = u64;
+    type Output = u64;
+
+    fn init(&self) -> u64 { 0 }
+    #[inline]
+    fn update(&self, state: &mut u64, row: &Joined) { *state = (*state).max(row.right_payload); }
+    fn finish(&self, state: u64) -> u64 { state }
+}
+
+// Two aggregates computed in the same pass, e.g. (Count, Sum) for an average.
+impl<A: Aggregate, B: Aggregate> Aggregate for (A, B) {
+    type State = (A::State, B::State);
+    type Output = (A::Output, B::Output);
+
+    fn init(&self) -> Self::State { (self.0.init(), self.1.init()) }
+    #[inline]
+    fn update(&self, state: &mut Self::State, row: &Joined) {
+        self.0.update(&mut state.0, row);
+        self.1.update(&mut state.1, row);
+    }
+    fn finish(&self, state: Self::State) -> Self::Output { (self.0.finish(state.0), self.1.finish(state.1)) }
+}
+
+// One output row of a group-join: a dimension key with at least one match and
+// the aggregate over its joined rows.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Grouped<T> {
+    pub key: u64,
+    pub value: T
+}
+
+impl<T> Grouped<T> {
+    pub fn new(key: u64, value: T) -> Grouped<T> {
+        Grouped {key, value}
+    }
+}
+
+// Sink that folds the joined rows of a merge join into the aggregate states of
+// the distinct keys of the sorted left input. Rows arrive in key order, so a
+// cursor finds the state of each row.
+struct FoldSink<'a, A: Aggregate> {
+    aggregate: &'a A,
+    keys: &'a [u64],
+    states: &'a mut [Option<A::State>],
+    cursor: usize
+}
+
+impl<A: Aggregate> JoinSink<Joined> for FoldSink<'_, A> {
+    #[inline]
+    fn push(&mut self, row: Joined) {
+        while self.keys[self.cursor] < row.key {
+            self.cursor += 1;
+        }
+        let state = self.states[self.cursor].get_or_insert_with(|| self.aggregate.init());
+        self.aggregate.update(state, &row);
+    }
+}
+
+// Group-joins a sorted left input with several sorted right runs. A key's
+// matches may be spread over all runs, so its aggregate is only emitted after
+// the last run. The left input meets each run in blocks of CHECK_INTERVAL
+// tuples; ctx is checked between blocks and counts the folded rows as matches.
+fn group_join_runs<'r, A, S>(left: &[Tuple], runs: impl Iterator<Item = &'r [Tuple]>, aggregate: &A, ctx: &JoinContext, output: &mut S) -> Result<(), JoinError>
+where
+    A: Aggregate,
+    S: JoinSink<Grouped<A::Output>>
+{
+    let mut keys: Vec<u64> = left.iter().map(|t| t.key).collect();
+    keys.dedup();
+    let mut states: Vec<Option<A::State>> = keys.iter().map(|_| None).collect();
+
+    for run in runs {
+        let mut sink = FoldSink {aggregate, keys: &keys, states: &mut states, cursor: 0};
+        for block in left.chunks(CHECK_INTERVAL) {
+            ctx.check()?;
+            let mut counted = CountingSink::new(&mut sink);
+            join::merge_join_sorted(block, join::key_window(run, block[0].key, block[block.len() - 1].key), &mut counted);
+            if counted.count > 0 {
+                ctx.add_matches(counted.count);
+            }
+        }
+    }
+
+    for (key, state) in keys.into_iter().zip(states) {
+        if let Some(state) = state {
+            output.push(Grouped::new(key, aggregate.finish(state)));
+        }
+    }
+    Ok(())
+}
+
+// Sequential group-join of two sorted inputs.
+pub fn group_join_sorted<A, S>(left: &[Tuple], right: &[Tuple], aggregate: &A, output: &mut S)
+where
+    A: Aggregate,
+    S: JoinSink<Grouped<A::Output>>
+{
+    context::unbounded(|ctx| group_join_runs(left, std::iter::once(right), aggregate, ctx, output));
+}
+
+pub fn nested_loop_group_join<A: Aggregate>(left: &[Tuple], right: &[Tuple], aggregate: &A) -> Vec<Grouped<A::Output>> {
+    let mut left = left.to_vec();
+    left.sort_by_key(|t| t.key);
+
+    let mut output = Vec::new();
+    for group in left.chunk_by(|a, b| a.key == b.key) {
+        let mut state = None;
+        for lt in group {
+            for rt in right.iter().filter(|rt| rt.key == lt.key) {
+                aggregate.update(state.get_or_insert_with(|| aggregate.init()), &Joined::new(lt.key, lt.payload, rt.payload));
+            }
+        }
+        if let Some(state) = state {
+            output.push(Grouped::new(group[0].key, aggregate.finish(state)));
+        }
+    }
+    output
+}
+
+pub fn partitioned_mpsm_group_join<A>(left: Vec<Tuple>, right: Vec<Tuple>, aggregate: &A, thread_count: usize) -> Vec<Vec<Grouped<A::Output>>>
+where
+    A: Aggregate,
+    A::Output: Send
+{
+    partitioned_mpsm_group_join_into(left, right, aggregate, thread_count, Vec::new)
+}
+
+// P-MPSM group-join, grouped on the keys of the left (private) input. Radix
+// partitioning gives every key of the private input to exactly one worker, so
+// each worker finishes the aggregates of its partition on its own.
+pub fn partitioned_mpsm_group_join_into<A, S, F>(left: Vec<Tuple>, right: Vec<Tuple>, aggregate: &A, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    A: Aggregate,
+    S: JoinSink<Grouped<A::Output>> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| partitioned_mpsm_group_join_ctx(left, right, aggregate, thread_count, ctx, make_sink))
+}
+
+pub fn partitioned_mpsm_group_join_ctx<A, S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, aggregate: &A, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    A: Aggregate,
+    S: JoinSink<Grouped<A::Output>> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+    // One worker per radix partition, so any thread count is rounded to a
+    // partition count the radix partitioning accepts.
+    let thread_count = partition_count(thread_count);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+
+    // Phase 1
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    // Phase 2
+    let mut private_chunks = join::radix_partition_private_ctx(&left, thread_count, ctx)?;
+
+    let public = &right;
+    let make_sink = &make_sink;
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, private_chunk) in private_chunks.iter_mut().enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                // Phase 3
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
+
+                // Phase 4
+                let mut output = make_sink();
+                group_join_runs(private_chunk, public.chunks(public_chunk_size), aggregate, ctx, &mut output)?;
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn group_join_sorted_test() {
+        let left = [Tuple::new(1, 0), Tuple::new(2, 0), Tuple::new(2, 1), Tuple::new(5, 0)];
+        let right = [Tuple::new(1, 10), Tuple::new(1, 30), Tuple::new(2, 7), Tuple::new(3, 1), Tuple::new(6, 2)];
+
+        let mut output = Vec::new();
+        group_join_sorted(&left, &right, &(Count, Sum), &mut output);
+        // Key 2 has two dimension tuples, so its fact tuple joins twice
+        assert_eq!(output, vec![Grouped::new(1, (2, 40)), Grouped::new(2, (2, 14))]);
+    }
+
+    #[test]
+    fn compare_partitioned_mpsm_group_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+
+        let expected = nested_loop_group_join(&dimension, &fact, &(Count, (Sum, (Min, Max))));
+        assert!(!expected.is_empty());
+
+        for thread_count in [1, 2, 3, 4, 6] {
+            let output = partitioned_mpsm_group_join(dimension.clone(), fact.clone(), &(Count, (Sum, (Min, Max))), thread_count)
+                .into_iter().flatten().collect::<Vec<_>>();
+            assert!(infrastructure::table_eq(&expected, &output));
+        }
+    }
+
+    #[test]
+    fn group_join_counts_match_join() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = infrastructure::gen_zipf_tables(1000, 20000, 1.0, &mut rng);
+
+        let joined = join::partitioned_mpsm(dimension.clone(), fact.clone(), 4).into_iter().flatten().count() as u64;
+        let groups = partitioned_mpsm_group_join(dimension, fact, &Count, 4).into_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(groups.iter().map(|g| g.value).sum::<u64>(), joined);
+        assert!(groups.iter().all(|g| g.value > 0));
+    }
+}
@@ -0,0 +1,440 @@
+use std::{sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, thread};
+
+use crate::{histograms, parallel, sink::JoinSink, tuples::{Joined, Tuple}};
+
+// Number of build tuples per partition that keeps a partition and its hash
+// table (24 bytes per tuple) within a 256 KiB L2 cache.
+pub const CACHE_PARTITION_TUPLES: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RadixConfig {
+    // Total number of key bits used for partitioning. Each relation is split
+    // into 2^radix_bits partitions.
+    pub radix_bits: u32,
+    // Number of partitioning passes the radix bits are spread over. More passes
+    // keep the fan-out of each pass small enough for the TLB.
+    pub passes: u32
+}
+
+impl RadixConfig {
+    pub fn new(radix_bits: u32, passes: u32) -> RadixConfig {
+        assert!(passes > 0 && passes <= radix_bits);
+        assert!(radix_bits < 32);
+        RadixConfig {radix_bits, passes}
+    }
+
+    // Picks enough radix bits so that a partition of the build side has
+    // roughly `partition_size` tuples.
+    pub fn for_build_size(build_size: usize, partition_size: usize) -> RadixConfig {
+        let partitions = build_size.div_ceil(partition_size.max(1)).max(2);
+        let radix_bits = partitions.next_power_of_two().ilog2().min(24);
+        let passes = if radix_bits > 12 { 2 } else { 1 };
+        RadixConfig::new(radix_bits, passes)
+    }
+
+    // Returns the (shift, bits) pair used by every pass. The low key bits are
+    // consumed first, and earlier passes take the larger share of the bits.
+    fn pass_bits(&self) -> Vec<(u32, u32)> {
+        let mut out = Vec::new();
+        let mut shift = 0;
+        for pass in 0..self.passes {
+            let remaining = self.passes - pass;
+            let bits = (self.radix_bits - shift).div_ceil(remaining);
+            out.push((shift, bits));
+            shift += bits;
+        }
+        out
+    }
+}
+
+impl Default for RadixConfig {
+    // 2^11 partitions keeps the build partitions of a 16M tuple relation at
+    // about CACHE_PARTITION_TUPLES each.
+    fn default() -> RadixConfig {
+        RadixConfig::new(11, 1)
+    }
+}
+
+// Bucket chained hash table in the style of Balkesen et al. The tuples are
+// never copied; `buckets` holds 1 + the index of the head of each chain and
+// `next` links every tuple to the previous tuple that hashed to the same bucket.
+pub struct ChainedHashTable<'a> {
+    tuples: &'a [Tuple],
+    buckets: Vec<u32>,
+    next: Vec<u32>,
+    mask: u64,
+    shift: u32
+}
+
+impl<'a> ChainedHashTable<'a> {
+    // Keys are hashed on the bits above `shift`, which lets a radix partition
+    // skip the bits that are already equal for every tuple in it.
+    pub fn build(tuples: &'a [Tuple], shift: u32) -> ChainedHashTable<'a> {
+        assert!(tuples.len() < u32::MAX as usize);
+
+        let num_buckets = tuples.len().next_power_of_two().max(1);
+        let mask = (num_buckets - 1) as u64;
+        let mut buckets = vec![0u32; num_buckets];
+        let mut next = vec![0u32; tuples.len()];
+
+        for (i, t) in tuples.iter().enumerate() {
+            let bucket = ((t.key >> shift) & mask) as usize;
+            next[i] = buckets[bucket];
+            buckets[bucket] = (i + 1) as u32;
+        }
+
+        ChainedHashTable {tuples, buckets, next, mask, shift}
+    }
+
+    #[inline]
+    pub fn probe<F: FnMut(&Tuple)>(&self, key: u64, mut on_match: F) {
+        let mut entry = self.buckets[((key >> self.shift) & self.mask) as usize];
+        while entry != 0 {
+            let t = &self.tuples[(entry - 1) as usize];
+            if t.key == key {
+                on_match(t);
+            }
+            entry = self.next[(entry - 1) as usize];
+        }
+    }
+}
+
+// Runs every partitioning pass over one relation. The first pass partitions the
+// whole relation in parallel with the histogram / prefix sum / scatter phases.
+// Later passes refine each partition on its own, spreading partitions over the
+// workers.
+fn radix_partition_passes(table: &[Tuple], thread_count: usize, config: &RadixConfig) -> Vec<Vec<Tuple>> {
+    let passes = config.pass_bits();
+
+    // Pass 1
+    let (shift, bits) = passes[0];
+    let histograms = parallel::radix_histograms(table, thread_count, shift, bits);
+    let prefix_sums = histograms::prefix_sums(&histograms);
+    let mut partitions = parallel::radix_scatter(table, thread_count, shift, bits, &prefix_sums);
+
+    // Pass 2..n
+    for &(shift, bits) in &passes[1..] {
+        let next_task = AtomicUsize::new(0);
+        let current = &partitions;
+        let mut refined: Vec<Vec<Vec<Tuple>>> = vec![Vec::new(); current.len()];
+
+        thread::scope(|s| {
+            let mut handles = Vec::new();
+            for _ in 0..thread_count {
+                let next_task = &next_task;
+                handles.push(s.spawn(move || {
+                    let mut done = Vec::new();
+                    loop {
+                        let p = next_task.fetch_add(1, Ordering::Relaxed);
+                        if p >= current.len() {
+                            break;
+                        }
+                        done.push((p, parallel::radix_partition(&current[p], shift, bits)));
+                    }
+                    done
+                }));
+            }
+            for h in handles {
+                for (p, sub_partitions) in h.join().unwrap() {
+                    refined[p] = sub_partitions;
+                }
+            }
+        });
+
+        // Sub-partition j of partition i holds the keys whose partitioning bits
+        // read (j << previous bits) | i, so the order below is the same for
+        // both relations.
+        partitions = refined.into_iter().flatten().collect();
+    }
+
+    partitions
+}
+
+// Joins one pair of co-partitions. The hash table is built over the smaller side
+// and the output keeps left and right in their original roles.
+fn join_partition<S: JoinSink<Joined>>(left: &[Tuple], right: &[Tuple], shift: u32, output: &mut S) {
+    if left.is_empty() || right.is_empty() {
+        return;
+    }
+
+    if right.len() <= left.len() {
+        let table = ChainedHashTable::build(right, shift);
+        for lt in left {
+            table.probe(lt.key, |rt| output.push(Joined::new(lt.key, lt.payload, rt.payload)));
+        }
+    } else {
+        let table = ChainedHashTable::build(left, shift);
+        for rt in right {
+            table.probe(rt.key, |lt| output.push(Joined::new(rt.key, lt.payload, rt.payload)));
+        }
+    }
+}
+
+// Parallel radix hash join (Kim et al. 2009, Balkesen et al. 2013). Both
+// relations are radix partitioned on the low key bits so that every partition
+// of the build side fits in cache. The co-partitions are then joined
+// independently, with workers pulling partitions from a shared counter.
+pub fn radix_hash_join(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, config: &RadixConfig) -> Vec<Vec<Joined>> {
+    radix_hash_join_into(left, right, thread_count, config, Vec::new)
+}
+
+pub fn radix_hash_join_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, config: &RadixConfig, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let left_partitions = radix_partition_passes(&left, thread_count, config);
+    let right_partitions = radix_partition_passes(&right, thread_count, config);
+    assert!(left_partitions.len() == right_partitions.len());
+
+    let next_task = AtomicUsize::new(0);
+    let lp: &[Vec<Tuple>] = &left_partitions;
+    let rp: &[Vec<Tuple>] = &right_partitions;
+    let shift = config.radix_bits;
+    let make_sink = &make_sink;
+
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for _ in 0..thread_count {
+            let next_task = &next_task;
+            handles.push(s.spawn(move || {
+                let mut output = make_sink();
+                loop {
+                    let p = next_task.fetch_add(1, Ordering::Relaxed);
+                    if p >= lp.len() {
+                        break;
+                    }
+                    join_partition(&lp[p], &rp[p], shift, &mut output);
+                }
+                output
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+// Open addressing hash table shared by all workers of the no partitioning join.
+// Workers insert concurrently by claiming an empty slot with a compare exchange
+// on its occupied flag, then filling in the tuple. Duplicate keys take separate
+// slots, so a probe walks the cluster until the first empty slot.
+pub struct SharedHashTable {
+    occupied: Vec<AtomicBool>,
+    keys: Vec<AtomicU64>,
+    payloads: Vec<AtomicU64>,
+    bits: u32
+}
+
+impl SharedHashTable {
+    // The table is sized to at most half full so probe sequences stay short.
+    pub fn with_capacity(capacity: usize) -> SharedHashTable {
+        let num_slots = (2 * capacity).next_power_of_two().max(2);
+        SharedHashTable {
+            occupied: (0..num_slots).map(|_| AtomicBool::new(false)).collect(),
+            keys: (0..num_slots).map(|_| AtomicU64::new(0)).collect(),
+            payloads: (0..num_slots).map(|_| AtomicU64::new(0)).collect(),
+            bits: num_slots.ilog2()
+        }
+    }
+
+    // Fibonacci hashing spreads dense and clustered keys over the whole table.
+    #[inline]
+    fn slot(&self, key: u64) -> usize {
+        (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - self.bits)) as usize
+    }
+
+    pub fn insert(&self, t: &Tuple) {
+        let mask = self.occupied.len() - 1;
+        let mut slot = self.slot(t.key);
+        loop {
+            if self.occupied[slot].compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                self.keys[slot].store(t.key, Ordering::Relaxed);
+                self.payloads[slot].store(t.payload, Ordering::Relaxed);
+                return;
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    // Must only be called once every insert has finished. The end of the
+    // build phase's thread scope provides the required synchronization.
+    #[inline]
+    pub fn probe<F: FnMut(u64)>(&self, key: u64, mut on_match: F) {
+        let mask = self.occupied.len() - 1;
+        let mut slot = self.slot(key);
+        while self.occupied[slot].load(Ordering::Relaxed) {
+            if self.keys[slot].load(Ordering::Relaxed) == key {
+                on_match(self.payloads[slot].load(Ordering::Relaxed));
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+}
+
+// No partitioning join (Blanas et al. 2011). All workers build one shared hash
+// table over the smaller relation, then probe it with chunks of the larger one.
+// Returns one output vector per worker like basic_mpsm.
+pub fn no_partitioning_join(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+    no_partitioning_join_into(left, right, thread_count, Vec::new)
+}
+
+pub fn no_partitioning_join_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let build_left = left.len() < right.len();
+    let (build, probe) = if build_left { (&left, &right) } else { (&right, &left) };
+
+    let table = SharedHashTable::with_capacity(build.len());
+    let table = &table;
+
+    // Build phase
+    let build_chunk_size = build.len().div_ceil(thread_count).max(1);
+    thread::scope(|s| {
+        for chunk in build.chunks(build_chunk_size) {
+            s.spawn(move || {
+                for t in chunk {
+                    table.insert(t);
+                }
+            });
+        }
+    });
+
+    // Probe phase
+    let probe_chunk_size = probe.len().div_ceil(thread_count).max(1);
+    let make_sink = &make_sink;
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for chunk in probe.chunks(probe_chunk_size) {
+            handles.push(s.spawn(move || {
+                let mut output = make_sink();
+                for pt in chunk {
+                    table.probe(pt.key, |payload| {
+                        let joined = if build_left {
+                            Joined::new(pt.key, payload, pt.payload)
+                        } else {
+                            Joined::new(pt.key, pt.payload, payload)
+                        };
+                        output.push(joined);
+                    });
+                }
+                output
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{infrastructure, join};
+
+    use super::*;
+
+    #[test]
+    fn pass_bits_test() {
+        assert_eq!(RadixConfig::new(11, 1).pass_bits(), vec![(0, 11)]);
+        assert_eq!(RadixConfig::new(11, 2).pass_bits(), vec![(0, 6), (6, 5)]);
+        assert_eq!(RadixConfig::new(9, 3).pass_bits(), vec![(0, 3), (3, 3), (6, 3)]);
+    }
+
+    #[test]
+    fn chained_hash_table_test() {
+        let tuples = vec![Tuple::new(4, 1), Tuple::new(8, 2), Tuple::new(4, 3), Tuple::new(5, 4)];
+        let table = ChainedHashTable::build(&tuples, 0);
+
+        let mut found = Vec::new();
+        table.probe(4, |t| found.push(t.payload));
+        found.sort();
+        assert_eq!(found, vec![1, 3]);
+
+        let mut missing = 0;
+        table.probe(6, |_| missing += 1);
+        assert_eq!(missing, 0);
+    }
+
+    #[test]
+    fn compare_radix_hash_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+
+        let nl_output = join::nested_loop_join(&lt, &rt);
+        for config in [RadixConfig::new(4, 1), RadixConfig::new(7, 2), RadixConfig::new(9, 3)] {
+            let hj_output = radix_hash_join(lt.clone(), rt.clone(), 4, &config)
+                .into_iter().flatten().collect::<Vec<Joined>>();
+            assert!(infrastructure::table_eq(&nl_output, &hj_output));
+        }
+    }
+
+    #[test]
+    fn radix_hash_join_dense_keys() {
+        // Dense keys put all the information in the low bits
+        let lt: Vec<Tuple> = (0..5000).map(|k| Tuple::new(k % 1000, k)).collect();
+        let rt: Vec<Tuple> = (0..2000).map(|k| Tuple::new(k, k * 3)).collect();
+
+        let nl_output = join::nested_loop_join(&lt, &rt);
+        let hj_output = radix_hash_join(lt, rt, 3, &RadixConfig::new(6, 2))
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &hj_output));
+    }
+
+    #[test]
+    fn radix_hash_join_empty() {
+        let rt = vec![Tuple::new(1, 1)];
+        let output = radix_hash_join(Vec::new(), rt, 4, &RadixConfig::default());
+        assert!(output.iter().all(|o| o.is_empty()));
+    }
+
+    #[test]
+    fn shared_hash_table_test() {
+        let table = SharedHashTable::with_capacity(4);
+        thread::scope(|s| {
+            let table = &table;
+            for t in [Tuple::new(4, 1), Tuple::new(8, 2), Tuple::new(4, 3), Tuple::new(0, 4)] {
+                s.spawn(move || table.insert(&t));
+            }
+        });
+
+        let mut found = Vec::new();
+        table.probe(4, |payload| found.push(payload));
+        found.sort();
+        assert_eq!(found, vec![1, 3]);
+
+        let mut zero = Vec::new();
+        table.probe(0, |payload| zero.push(payload));
+        assert_eq!(zero, vec![4]);
+    }
+
+    #[test]
+    fn compare_no_partitioning_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+
+        let nl_output = join::nested_loop_join(&lt, &rt);
+        let npj_output = no_partitioning_join(lt.clone(), rt.clone(), 4)
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &npj_output));
+
+        // Swap the inputs so that the hash table is built over the other side
+        let nl_output = join::nested_loop_join(&rt, &lt);
+        let npj_output = no_partitioning_join(rt, lt, 4)
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &npj_output));
+    }
+}
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use crate::tuples::Tuple;
+
 pub fn prefix_sums(histograms: &Vec<Vec<u64>>) -> Vec<Vec<u64>> {
     let num_histograms = histograms.len();
     assert!(num_histograms > 0);
@@ -21,6 +25,108 @@ pub fn prefix_sums(histograms: &Vec<Vec<u64>>) -> Vec<Vec<u64>> {
     ps
 }
 
+// Computes parts - 1 splitters that divide the sorted runs into parts key
+// ranges holding roughly the same number of tuples. Every run contributes an
+// equi-depth sample of up to samples_per_run keys, and each sampled key stands
+// for the tuples of its run up to the next sampled key.
+pub fn equi_depth_splitters(runs: &[&[Tuple]], parts: usize, samples_per_run: usize) -> Vec<u64> {
+    assert!(parts > 0);
+    assert!(samples_per_run > 0);
+
+    let mut samples: Vec<(u64, usize)> = Vec::new();
+    for run in runs {
+        debug_assert!(run.is_sorted_by_key(|t| t.key));
+        let k = samples_per_run.min(run.len());
+        for i in 0..k {
+            let pos = i * run.len() / k;
+            let next = (i + 1) * run.len() / k;
+            samples.push((run[pos].key, next - pos));
+        }
+    }
+    samples.sort_unstable_by_key(|s| s.0);
+
+    let total: usize = samples.iter().map(|s| s.1).sum();
+    let mut splitters = Vec::with_capacity(parts - 1);
+    let mut seen = 0;
+    for (key, weight) in samples {
+        // A splitter is the first key of the next range, so it is chosen before
+        // its own weight is counted.
+        while splitters.len() < parts - 1 && seen * parts >= total * (splitters.len() + 1) {
+            splitters.push(key);
+        }
+        seen += weight;
+    }
+    // Ranges past the largest sampled key are left empty.
+    splitters.resize(parts - 1, u64::MAX);
+
+    splitters
+}
+
+// (key, count) pairs of a frequent items summary.
+pub type KeySummary = Vec<(u64, u64)>;
+
+// Misra-Gries frequent items summary over the keys of the tuples. Keeps at most
+// `capacity` counters. Every key that occurs more than n / (capacity + 1) times
+// is guaranteed to be in the summary, with a count that underestimates its
+// frequency by at most n / (capacity + 1).
+pub fn misra_gries<'a>(tuples: impl Iterator<Item = &'a Tuple>, capacity: usize) -> KeySummary {
+    let mut summary = MisraGries::new(capacity);
+    for t in tuples {
+        summary.insert(t.key);
+    }
+    summary.into_summary()
+}
+
+// misra_gries fed one key at a time, for passes that stop between blocks.
+#[derive(Clone, Debug)]
+pub struct MisraGries {
+    counters: HashMap<u64, u64>,
+    capacity: usize
+}
+
+impl MisraGries {
+    pub fn new(capacity: usize) -> MisraGries {
+        assert!(capacity > 0);
+        MisraGries {counters: HashMap::with_capacity(capacity + 1), capacity}
+    }
+
+    pub fn insert(&mut self, key: u64) {
+        if let Some(c) = self.counters.get_mut(&key) {
+            *c += 1;
+        } else if self.counters.len() < self.capacity {
+            self.counters.insert(key, 1);
+        } else {
+            self.counters.retain(|_, c| {
+                *c -= 1;
+                *c > 0
+            });
+        }
+    }
+
+    pub fn into_summary(self) -> KeySummary {
+        self.counters.into_iter().collect()
+    }
+}
+
+// Merges per-chunk summaries and returns, in increasing order, the keys whose
+// estimated count exceeds threshold. Summaries built from every stride-th tuple
+// have their counts scaled back up by stride.
+pub fn heavy_hitters(summaries: &[KeySummary], stride: u64, threshold: u64) -> Vec<u64> {
+    let mut totals: HashMap<u64, u64> = HashMap::new();
+    for summary in summaries {
+        for (key, count) in summary {
+            *totals.entry(*key).or_insert(0) += count * stride;
+        }
+    }
+
+    let mut keys: Vec<u64> = totals.into_iter()
+        .filter(|(_, count)| *count > threshold)
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort_unstable();
+    keys
+}
+
 #[cfg(test)]
 mod test {
 
@@ -43,4 +149,51 @@ mod test {
         let ps = prefix_sums(&histograms);
         assert_eq!(ps, expected_ps);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn equi_depth_splitters_test() {
+        let run1: Vec<Tuple> = (0..100).map(|k| Tuple::new(k, 0)).collect();
+        let run2: Vec<Tuple> = (100..200).map(|k| Tuple::new(k, 0)).collect();
+
+        let splitters = equi_depth_splitters(&[&run1, &run2], 4, 100);
+        assert_eq!(splitters, vec![50, 100, 150]);
+    }
+
+    #[test]
+    fn equi_depth_splitters_skewed() {
+        // Three quarters of the tuples sit in the narrow key range of the
+        // second run, so it must be split between three partitions
+        let run1: Vec<Tuple> = (0..40).map(|k| Tuple::new(k, 0)).collect();
+        let run2: Vec<Tuple> = (1000..1120).map(|k| Tuple::new(k, 0)).collect();
+
+        let splitters = equi_depth_splitters(&[&run1, &run2], 4, 16);
+        assert_eq!(splitters.len(), 3);
+        assert!(splitters.is_sorted());
+        assert!(splitters[0] <= 1000 && splitters[1] > 1000 && splitters[2] < 1120);
+    }
+
+    #[test]
+    fn equi_depth_splitters_empty() {
+        assert_eq!(equi_depth_splitters(&[], 3, 8), vec![u64::MAX, u64::MAX]);
+    }
+
+    #[test]
+    fn misra_gries_finds_frequent_keys() {
+        let mut tuples: Vec<Tuple> = (0..1000).map(|k| Tuple::new(k, 0)).collect();
+        tuples.extend((0..600).map(|i| Tuple::new(7, i)));
+        tuples.extend((0..400).map(|i| Tuple::new(9, i)));
+
+        let summary = misra_gries(tuples.iter(), 4);
+        assert!(summary.len() <= 4);
+        // Keys 7 and 9 occur more than 2000 / 5 times
+        assert!(summary.iter().any(|(k, _)| *k == 7));
+        assert!(summary.iter().any(|(k, _)| *k == 9));
+    }
+
+    #[test]
+    fn heavy_hitters_test() {
+        let summaries = vec![vec![(3, 10), (5, 2)], vec![(3, 4), (8, 9)]];
+        assert_eq!(heavy_hitters(&summaries, 1, 8), vec![3, 8]);
+        assert_eq!(heavy_hitters(&summaries, 2, 20), vec![3]);
+    }
+}
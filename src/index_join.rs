@@ -0,0 +1,167 @@
+use std::thread;
+
+use crate::{affinity, search, sink::JoinSink, tuples::{Joined, Tuple}};
+
+// Lower bound search an index nested-loop join probes the inner relation with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStrategy {
+    Linear,
+    Binary,
+    Interpolation,
+    Exponential
+}
+
+impl SearchStrategy {
+    #[inline]
+    pub fn lower_bound(&self, target: u64, keys: &[u64]) -> Option<usize> {
+        match self {
+            SearchStrategy::Linear => search::lb_linear_search(target, keys),
+            SearchStrategy::Binary => search::lb_binary_search(target, keys),
+            SearchStrategy::Interpolation => search::lb_interpolation_search(target, keys),
+            SearchStrategy::Exponential => search::lb_exponential_search(target, keys)
+        }
+    }
+}
+
+// Inner relation of an index nested-loop join, sorted by key. Keys and
+// payloads are stored apart so the searches only touch keys.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SortedIndex {
+    keys: Vec<u64>,
+    payloads: Vec<u64>
+}
+
+impl SortedIndex {
+    pub fn build(mut inner: Vec<Tuple>) -> SortedIndex {
+        inner.sort_by_key(|t| t.key);
+        SortedIndex::from_sorted(&inner)
+    }
+
+    // For an inner relation that is already sorted by key.
+    pub fn from_sorted(inner: &[Tuple]) -> SortedIndex {
+        assert!(inner.is_sorted_by_key(|t| t.key), "inner relation must be sorted by key");
+        SortedIndex {
+            keys: inner.iter().map(|t| t.key).collect(),
+            payloads: inner.iter().map(|t| t.payload).collect()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Pushes a joined row for every inner tuple with the key of `outer`.
+    #[inline]
+    pub fn probe<S: JoinSink<Joined>>(&self, outer: &Tuple, strategy: SearchStrategy, output: &mut S) {
+        let Some(start) = strategy.lower_bound(outer.key, &self.keys) else { return };
+        for (key, payload) in self.keys[start..].iter().zip(&self.payloads[start..]) {
+            if *key != outer.key {
+                break;
+            }
+            output.push(Joined::new(outer.key, outer.payload, *payload));
+        }
+    }
+}
+
+pub fn index_nested_loop_join(left: &[Tuple], right: Vec<Tuple>, strategy: SearchStrategy, thread_count: usize) -> Vec<Vec<Joined>> {
+    let index = SortedIndex::build(right);
+    index_nested_loop_join_into(left, &index, strategy, thread_count, Vec::new)
+}
+
+// Probes the index once per left (outer) tuple. The outer relation is split
+// into thread_count chunks that are probed in parallel and need not be sorted.
+pub fn index_nested_loop_join_into<S, F>(left: &[Tuple], index: &SortedIndex, strategy: SearchStrategy, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let chunk_size = left.len().div_ceil(thread_count).max(1);
+    let make_sink = &make_sink;
+
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, outer_chunk) in left.chunks(chunk_size).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                let mut output = make_sink();
+                for t in outer_chunk {
+                    index.probe(t, strategy, &mut output);
+                }
+                output
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{infrastructure, join};
+
+    use super::*;
+
+    const STRATEGIES: [SearchStrategy; 4] = [
+        SearchStrategy::Linear,
+        SearchStrategy::Binary,
+        SearchStrategy::Interpolation,
+        SearchStrategy::Exponential
+    ];
+
+    #[test]
+    fn probe_duplicates() {
+        let index = SortedIndex::build(vec![Tuple::new(5, 1), Tuple::new(3, 2), Tuple::new(5, 3), Tuple::new(9, 4)]);
+        for strategy in STRATEGIES {
+            let mut output = Vec::new();
+            index.probe(&Tuple::new(5, 7), strategy, &mut output);
+            index.probe(&Tuple::new(4, 8), strategy, &mut output);
+            index.probe(&Tuple::new(10, 9), strategy, &mut output);
+            assert_eq!(output, vec![Joined::new(5, 7, 1), Joined::new(5, 7, 3)], "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn compare_index_nested_loop_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+
+        // Dimension table as the inner relation, and the fact table with its
+        // duplicate keys as the inner relation
+        for (outer, inner) in [(&lt, &rt), (&rt, &lt)] {
+            let expected = join::nested_loop_join(outer, inner);
+            for strategy in STRATEGIES {
+                for thread_count in [1, 3] {
+                    let output = index_nested_loop_join(outer, inner.clone(), strategy, thread_count)
+                        .into_iter().flatten().collect::<Vec<Joined>>();
+                    assert!(infrastructure::table_eq(&expected, &output), "{strategy:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn index_from_sorted_inner() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, mut rt) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+        let expected = join::nested_loop_join(&lt, &rt);
+
+        rt.sort_by_key(|t| t.key);
+        let index = SortedIndex::from_sorted(&rt);
+        assert_eq!(index.len(), rt.len());
+
+        let output = index_nested_loop_join_into(&lt, &index, SearchStrategy::Interpolation, 4, Vec::new)
+            .into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+    }
+}
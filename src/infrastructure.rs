@@ -58,6 +58,51 @@ pub fn gen_tables<R: Rng>(n: usize, p: f64, rng: &mut R) -> (Vec<Tuple>, Vec<Tup
     (fact_table, dimension_table)
 }
 
+// Draws n foreign keys from key_set with Zipfian popularity: the i-th key is
+// picked with probability proportional to 1 / (i + 1)^s. s = 0 is uniform, and
+// around s = 1 the first few keys make up a large share of the output.
+pub fn gen_zipf_fact_keys<R: Rng>(key_set: &[u64], n: usize, s: f64, rng: &mut R) -> Vec<u64> {
+    assert!(!key_set.is_empty(), "key set must not be empty");
+    assert!(s >= 0.0, "s must not be negative");
+
+    let mut cdf = Vec::with_capacity(key_set.len());
+    let mut total = 0.0;
+    for i in 0..key_set.len() {
+        total += 1.0 / ((i + 1) as f64).powf(s);
+        cdf.push(total);
+    }
+
+    let mut output = Vec::with_capacity(n);
+    for _ in 0..n {
+        let x = rng.random::<f64>() * total;
+        let i = cdf.partition_point(|c| *c <= x).min(key_set.len() - 1);
+        output.push(key_set[i]);
+    }
+    output
+}
+
+// gen_tables with Zipfian foreign keys: n_fact fact tuples reference the n
+// dimension keys with skew s.
+pub fn gen_zipf_tables<R: Rng>(n: usize, n_fact: usize, s: f64, rng: &mut R) -> (Vec<Tuple>, Vec<Tuple>) {
+    let dimension_keys = gen_keys(n, rng);
+    let dimension_payloads = gen_keys(n, rng);
+    let dimension_table = zip_table(&dimension_keys, &dimension_payloads);
+
+    let fact_keys = gen_zipf_fact_keys(&dimension_keys, n_fact, s, rng);
+    let fact_payloads = gen_keys(fact_keys.len(), rng);
+    let fact_table = zip_table(&fact_keys, &fact_payloads);
+
+    (fact_table, dimension_table)
+}
+
+// Random directed graph as an edge table: Tuple { key: src, payload: dst }
+// with both endpoints drawn uniformly from vertex_count vertices.
+pub fn gen_edges<R: Rng>(vertex_count: u64, edge_count: usize, rng: &mut R) -> Vec<Tuple> {
+    (0..edge_count)
+        .map(|_| Tuple::new(rng.random_range(0..vertex_count), rng.random_range(0..vertex_count)))
+        .collect()
+}
+
 pub fn table_eq<T>(left: &[T], right: &[T]) -> bool
     where T: Eq + Hash
 {
@@ -96,6 +141,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn zipf_keys_are_skewed() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let key_set: Vec<u64> = (0..1000).collect();
+        let keys = gen_zipf_fact_keys(&key_set, 100000, 1.0, &mut rng);
+
+        assert_eq!(keys.len(), 100000);
+        let first = keys.iter().filter(|k| **k == 0).count();
+        let tenth = keys.iter().filter(|k| **k == 9).count();
+        // H(1000) is about 7.5, so key 0 is drawn about 13% of the time
+        assert!(first > 11000 && first < 16000, "{first}");
+        assert!(tenth < first / 5);
+    }
+
     #[test]
     fn table_eq_test1() {
         let mut rng = StdRng::seed_from_u64(101);
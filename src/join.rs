@@ -1,12 +1,16 @@
 #![allow(dead_code)]
 
-use std::{cmp::Ordering, thread};
+use std::{cmp::Ordering, ops::Range, sync::atomic::{self, AtomicBool}, thread, time::{Duration, Instant}};
 
-use crate::{histograms, parallel, search, tuples::{Joined, Tuple}};
+use crate::{affinity, bloom::{BlockedBloomFilter, BloomConfig, BloomStats}, context::{self, CountingSink, JoinContext, JoinError, CHECK_INTERVAL}, histograms, merge, parallel, search, sink::JoinSink, tuples::{Joined, OuterJoined, Tuple}};
 
-fn nested_loop_join(left: &Vec<Tuple>, right: &Vec<Tuple>) -> Vec<Joined> {
+pub fn nested_loop_join(left: &[Tuple], right: &[Tuple]) -> Vec<Joined> {
     let mut output = Vec::new();
+    nested_loop_join_into(left, right, &mut output);
+    output
+}
 
+pub fn nested_loop_join_into<S: JoinSink<Joined>>(left: &[Tuple], right: &[Tuple], output: &mut S) {
     for lt in left {
         for rt in right {
             if lt.key == rt.key {
@@ -14,25 +18,110 @@ fn nested_loop_join(left: &Vec<Tuple>, right: &Vec<Tuple>) -> Vec<Joined> {
             }
         }
     }
+}
 
-    output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinType {
+    Inner,
+    LeftOuter,
+    RightOuter,
+    FullOuter
 }
 
-fn merge_join_sorted(left: &[Tuple], right: &[Tuple], output: &mut Vec<Joined>) {
-    if left.len() == 0 || right.len() == 0 {
-        return 
+impl JoinType {
+    // Whether unmatched left tuples are part of the output
+    pub fn keeps_left(&self) -> bool {
+        matches!(self, JoinType::LeftOuter | JoinType::FullOuter)
     }
 
-    let mut li = 0;
-    let mut ri = 0;
+    // Whether unmatched right tuples are part of the output
+    pub fn keeps_right(&self) -> bool {
+        matches!(self, JoinType::RightOuter | JoinType::FullOuter)
+    }
+}
+
+// Semi joins keep the left tuples that have a match in the right input, anti
+// joins keep the ones that do not. Either way every left tuple is emitted at
+// most once and no joined rows are built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinFilter {
+    Semi,
+    Anti
+}
+
+impl JoinFilter {
+    #[inline]
+    fn keeps(&self, matched: bool) -> bool {
+        match self {
+            JoinFilter::Semi => matched,
+            JoinFilter::Anti => !matched
+        }
+    }
+}
+
+pub fn nested_loop_join_filter(left: &[Tuple], right: &[Tuple], filter: JoinFilter) -> Vec<Tuple> {
+    left.iter()
+        .filter(|lt| filter.keeps(right.iter().any(|rt| rt.key == lt.key)))
+        .copied()
+        .collect()
+}
+
+pub fn nested_loop_join_outer(left: &[Tuple], right: &[Tuple], join_type: JoinType) -> Vec<OuterJoined> {
+    let mut output = Vec::new();
+    let mut right_matched = vec![false; right.len()];
 
-    if left[li].key < right[ri].key {
-        match search::lb_binary_search_by_key(&right[ri].key, left, |t| &t.key) {
-            Some(i) => li = i,
-            None => return // left and right do not overlap
+    for lt in left {
+        let mut matched = false;
+        for (j, rt) in right.iter().enumerate() {
+            if lt.key == rt.key {
+                output.push(OuterJoined::new(lt.key, Some(lt.payload), Some(rt.payload)));
+                matched = true;
+                right_matched[j] = true;
+            }
+        }
+        if !matched && join_type.keeps_left() {
+            output.push(OuterJoined::left_only(lt));
         }
     }
 
+    if join_type.keeps_right() {
+        for (rt, matched) in right.iter().zip(right_matched) {
+            if !matched {
+                output.push(OuterJoined::right_only(rt));
+            }
+        }
+    }
+
+    output
+}
+
+// Returns the index range of a sorted run whose keys lie in [lo, hi].
+pub fn key_window_range(run: &[Tuple], lo: u64, hi: u64) -> Range<usize> {
+    debug_assert!(lo <= hi);
+    let start = search::lb_binary_search_by_key(&lo, run, |t| &t.key).unwrap_or(run.len());
+    let end = match hi.checked_add(1) {
+        Some(past_hi) => search::lb_binary_search_by_key(&past_hi, &run[start..], |t| &t.key)
+            .map_or(run.len(), |i| start + i),
+        None => run.len()
+    };
+    start..end
+}
+
+// Returns the part of a sorted run whose keys lie in [lo, hi].
+pub fn key_window(run: &[Tuple], lo: u64, hi: u64) -> &[Tuple] {
+    &run[key_window_range(run, lo, hi)]
+}
+
+// Walks two sorted inputs and calls on_group with the index ranges of every key
+// that appears in both, in increasing key order.
+#[inline]
+pub fn for_each_match_group<F>(left: &[Tuple], right: &[Tuple], mut on_group: F)
+where
+    F: FnMut(Range<usize>, Range<usize>)
+{
+    let mut li = 0;
+    let mut ri = 0;
+
     while li < left.len() && ri < right.len() {
         match left[li].key.cmp(&right[ri].key) {
             Ordering::Less => {li += 1;}
@@ -47,114 +136,1085 @@ fn merge_join_sorted(left: &[Tuple], right: &[Tuple], output: &mut Vec<Joined>)
                 let r_start = ri;
                 while ri < right.len() && right[ri].key == key { ri += 1; }
 
-                for i in l_start..li {
-                    for j in r_start..ri {
-                        output.push(Joined::new(key, left[i].payload, right[j].payload));
-                    }
+                on_group(l_start..li, r_start..ri);
+            }
+        }
+    }
+}
+
+// Only the overlapping key ranges of left and right can produce matches. Trims
+// the front and the tail of both sorted inputs down to that overlap.
+pub fn overlap<'a>(left: &'a [Tuple], right: &'a [Tuple]) -> (&'a [Tuple], &'a [Tuple]) {
+    if left.is_empty() || right.is_empty() {
+        return (&left[0..0], &right[0..0])
+    }
+
+    let left = key_window(left, right[0].key, right[right.len() - 1].key);
+    if left.is_empty() {
+        return (left, &right[0..0]) // left and right do not overlap
+    }
+    let right = key_window(right, left[0].key, left[left.len() - 1].key);
+    (left, right)
+}
+
+// Eager counterpart of stream::MergeJoinIter that pushes every match into a sink.
+pub fn merge_join_sorted<S: JoinSink<Joined>>(left: &[Tuple], right: &[Tuple], output: &mut S) {
+    let (left, right) = overlap(left, right);
+    merge_join_groups(left, right, output);
+}
+
+// merge_join_sorted without the overlap search, for callers that have
+// already narrowed the inputs.
+#[inline]
+fn merge_join_groups<S: JoinSink<Joined>>(left: &[Tuple], right: &[Tuple], output: &mut S) {
+    for_each_match_group(left, right, |l_range, r_range| {
+        for lt in &left[l_range] {
+            for rt in &right[r_range.clone()] {
+                output.push(Joined::new(lt.key, lt.payload, rt.payload));
+            }
+        }
+    });
+}
+
+// Number of consecutive steps a merge cursor takes on its own before
+// galloping_merge_join_sorted switches it to exponential search.
+pub const GALLOP_THRESHOLD: usize = 8;
+
+// for_each_match_group that adapts to inputs of very different density. A
+// cursor that keeps falling behind the other one stops stepping one tuple at
+// a time and gallops to the next key of the other side instead.
+#[inline]
+pub fn for_each_match_group_galloping<F>(left: &[Tuple], right: &[Tuple], mut on_group: F)
+where
+    F: FnMut(Range<usize>, Range<usize>)
+{
+    let mut li = 0;
+    let mut ri = 0;
+    // Consecutive steps of the left and the right cursor
+    let mut l_steps = 0;
+    let mut r_steps = 0;
+
+    while li < left.len() && ri < right.len() {
+        match left[li].key.cmp(&right[ri].key) {
+            Ordering::Less => {
+                if l_steps < GALLOP_THRESHOLD {
+                    li += 1;
+                    l_steps += 1;
+                } else {
+                    li += search::lb_exponential_search_by_key(&right[ri].key, &left[li..], |t| &t.key)
+                        .unwrap_or(left.len() - li);
+                }
+                r_steps = 0;
+            }
+            Ordering::Greater => {
+                if r_steps < GALLOP_THRESHOLD {
+                    ri += 1;
+                    r_steps += 1;
+                } else {
+                    ri += search::lb_exponential_search_by_key(&left[li].key, &right[ri..], |t| &t.key)
+                        .unwrap_or(right.len() - ri);
                 }
+                l_steps = 0;
+            }
+            Ordering::Equal => {
+                let key = left[li].key;
+
+                let l_start = li;
+                while li < left.len() && left[li].key == key { li += 1; }
+
+                let r_start = ri;
+                while ri < right.len() && right[ri].key == key { ri += 1; }
+
+                l_steps = 0;
+                r_steps = 0;
+                on_group(l_start..li, r_start..ri);
             }
         }
     }
 }
 
-fn basic_sort_merge_join(mut left: Vec<Tuple>, mut right: Vec<Tuple>) -> Vec<Joined> {
+// merge_join_sorted on for_each_match_group_galloping. When one input is much
+// smaller than the other, the cost is close to |small| * log(|large| / |small|)
+// instead of |small| + |large|.
+pub fn galloping_merge_join_sorted<S: JoinSink<Joined>>(left: &[Tuple], right: &[Tuple], output: &mut S) {
+    let (left, right) = overlap(left, right);
+
+    for_each_match_group_galloping(left, right, |l_range, r_range| {
+        for lt in &left[l_range] {
+            for rt in &right[r_range.clone()] {
+                output.push(Joined::new(lt.key, lt.payload, rt.payload));
+            }
+        }
+    });
+}
+
+// Outer variant of merge_join_sorted. Unmatched tuples of the kept sides are
+// emitted with a missing payload for the other side.
+pub fn merge_join_sorted_outer<S: JoinSink<OuterJoined>>(left: &[Tuple], right: &[Tuple], join_type: JoinType, output: &mut S) {
+    let mut l_next = 0;
+    let mut r_next = 0;
+
+    for_each_match_group(left, right, |l_range, r_range| {
+        if join_type.keeps_left() {
+            left[l_next..l_range.start].iter().for_each(|t| output.push(OuterJoined::left_only(t)));
+        }
+        if join_type.keeps_right() {
+            right[r_next..r_range.start].iter().for_each(|t| output.push(OuterJoined::right_only(t)));
+        }
+        for lt in &left[l_range.clone()] {
+            for rt in &right[r_range.clone()] {
+                output.push(OuterJoined::new(lt.key, Some(lt.payload), Some(rt.payload)));
+            }
+        }
+        l_next = l_range.end;
+        r_next = r_range.end;
+    });
+
+    if join_type.keeps_left() {
+        left[l_next..].iter().for_each(|t| output.push(OuterJoined::left_only(t)));
+    }
+    if join_type.keeps_right() {
+        right[r_next..].iter().for_each(|t| output.push(OuterJoined::right_only(t)));
+    }
+}
+
+// Semi or anti join of two sorted inputs. Runs of duplicate keys are skipped
+// as a whole instead of being paired up.
+pub fn merge_join_sorted_filter<S: JoinSink<Tuple>>(left: &[Tuple], right: &[Tuple], filter: JoinFilter, output: &mut S) {
+    let mut l_next = 0;
+
+    for_each_match_group(left, right, |l_range, _| {
+        let kept = match filter {
+            JoinFilter::Semi => &left[l_range.clone()],
+            JoinFilter::Anti => &left[l_next..l_range.start]
+        };
+        kept.iter().for_each(|t| output.push(*t));
+        l_next = l_range.end;
+    });
+
+    if filter == JoinFilter::Anti {
+        left[l_next..].iter().for_each(|t| output.push(*t));
+    }
+}
+
+pub fn basic_sort_merge_join(left: Vec<Tuple>, right: Vec<Tuple>) -> Vec<Joined> {
+    let mut output = Vec::new();
+    basic_sort_merge_join_into(left, right, &mut output);
+    output
+}
+
+pub fn basic_sort_merge_join_into<S: JoinSink<Joined>>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, output: &mut S) {
     left.sort_by_key(|t| t.key);
     right.sort_by_key(|t| t.key);
 
+    merge_join_sorted(&left, &right, output);
+}
+
+pub fn galloping_sort_merge_join(left: Vec<Tuple>, right: Vec<Tuple>) -> Vec<Joined> {
+    let mut output = Vec::new();
+    galloping_sort_merge_join_into(left, right, &mut output);
+    output
+}
+
+pub fn galloping_sort_merge_join_into<S: JoinSink<Joined>>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, output: &mut S) {
+    left.sort_by_key(|t| t.key);
+    right.sort_by_key(|t| t.key);
+    galloping_merge_join_sorted(&left, &right, output);
+}
+
+pub fn sort_merge_join_filter(left: Vec<Tuple>, right: Vec<Tuple>, filter: JoinFilter) -> Vec<Tuple> {
+    let mut output = Vec::new();
+    sort_merge_join_filter_into(left, right, filter, &mut output);
+    output
+}
+
+pub fn sort_merge_join_filter_into<S: JoinSink<Tuple>>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, filter: JoinFilter, output: &mut S) {
+    left.sort_by_key(|t| t.key);
+    right.sort_by_key(|t| t.key);
+
+    merge_join_sorted_filter(&left, &right, filter, output);
+}
+
+pub fn sort_merge_join_outer(left: Vec<Tuple>, right: Vec<Tuple>, join_type: JoinType) -> Vec<OuterJoined> {
     let mut output = Vec::new();
-    merge_join_sorted(&left, &right, &mut output);
+    sort_merge_join_outer_into(left, right, join_type, &mut output);
     output
 }
 
-fn basic_mpsm(mut left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>>{
+pub fn sort_merge_join_outer_into<S: JoinSink<OuterJoined>>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, join_type: JoinType, output: &mut S) {
+    left.sort_by_key(|t| t.key);
+    right.sort_by_key(|t| t.key);
+
+    merge_join_sorted_outer(&left, &right, join_type, output);
+}
+
+pub fn basic_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>>{
+    basic_mpsm_into(left, right, thread_count, Vec::new)
+}
+
+// Every worker pushes its matches into its own sink made by make_sink. The
+// sinks are returned in worker order.
+pub fn basic_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| basic_mpsm_ctx(left, right, thread_count, ctx, make_sink))
+}
+
+// basic_mpsm_into that stops early, with an error, once ctx is cancelled or
+// past its deadline, and reports its progress to ctx.
+pub fn basic_mpsm_ctx<S, F>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
     assert!(thread_count > 0);
     
     // Sort the public data among thread_count workers
-    parallel::sort_runs_parallel(&mut right, thread_count);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
 
     // Borrow right as an immutable reference so that all threads
     // can share the data.
     let public: &[Tuple] = &right;
-    let private_chunk_size = left.len().div_ceil(thread_count);
-    let public_chunk_size = right.len().div_ceil(thread_count);
+    let private_chunk_size = left.len().div_ceil(thread_count).max(1);
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    let make_sink = &make_sink;
 
     // Sort each private data chunk and then merge against the 
     // entire public data.
-    let mut outputs = Vec::new();
     thread::scope(|s| {
         let mut handles = Vec::new();
-        for private_chunk in left.chunks_mut(private_chunk_size) {
-            handles.push(s.spawn(move || {
-                private_chunk.sort_by_key(|t| t.key);
+        for (worker, private_chunk) in left.chunks_mut(private_chunk_size).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
                 
-                let mut output = Vec::new();
-                for public_chunk in public.chunks(public_chunk_size) {
-                    merge_join_sorted(private_chunk, public_chunk, &mut output);
-                }
-                output
+                let mut output = make_sink();
+                merge_join_runs_ctx(private_chunk, public, public_chunk_size, ctx, &mut output)?;
+                Ok(output)
             }));
         }
-        for h in handles {
-            outputs.push(h.join().unwrap());
-        }
-    });
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
 
-    outputs
+pub fn partitioned_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+    partitioned_mpsm_into(left, right, thread_count, Vec::new)
 }
 
-fn partitioned_mpsm(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+pub fn partitioned_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| partitioned_mpsm_ctx(left, right, thread_count, ctx, make_sink))
+}
+
+pub fn partitioned_mpsm_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
     assert!(thread_count > 0);
 
     // left = private data = R
     // right = public data = S
 
-    let public_chunk_size = right.len().div_ceil(thread_count);
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
 
     // Phase 1 -- https://arxiv.org/abs/1207.0145
     // Sort the public data among thread_count workers
-    parallel::sort_runs_parallel(&mut right, thread_count);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
 
     // Phase 2
+    let mut private_chunks = radix_partition_private_ctx(&left, thread_count, ctx)?;
+
+    join_private_partitions(&mut private_chunks, &right, public_chunk_size, ctx, &make_sink)
+}
+
+// Phase 2 of P-MPSM on the top log2(thread_count) key bits.
+pub fn radix_partition_private(left: &[Tuple], thread_count: usize) -> Vec<Vec<Tuple>> {
+    context::unbounded(|ctx| radix_partition_private_ctx(left, thread_count, ctx))
+}
+
+pub fn radix_partition_private_ctx(left: &[Tuple], thread_count: usize, ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError> {
+    assert!(thread_count >= 2 && thread_count.is_power_of_two());
+    let bits_prefix = thread_count.ilog2();
+
     // Compute thread_count histograms on the private data using thread_count workers
-    let histograms = parallel::chunk_histograms(&left, thread_count);
+    let histograms = parallel::radix_histograms_ctx(left, thread_count, 64 - bits_prefix, bits_prefix, ctx)?;
     // Compute prefix sums
     let prefix_sums = histograms::prefix_sums(&histograms);
     // Scatter the private data into partitioned chunks
-    let mut private_chunks = parallel::scatter(&left, thread_count, &prefix_sums);
+    parallel::radix_scatter_ctx(left, thread_count, 64 - bits_prefix, bits_prefix, &prefix_sums, ctx)
+}
+
+// Inclusive key range covered by each partition of radix_partition_private.
+fn radix_partition_bounds(thread_count: usize) -> Vec<Option<(u64, u64)>> {
+    let bits_prefix = thread_count.ilog2();
+    let width = 1u64 << (64 - bits_prefix);
+    (0..thread_count as u64)
+        .map(|i| Some((i * width, i * width + (width - 1))))
+        .collect()
+}
+
+// Statistics a join reports next to its output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JoinStats {
+    pub bloom: Option<BloomStats>
+}
+
+pub fn bloom_partitioned_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, bloom: Option<BloomConfig>) -> (Vec<Vec<Joined>>, JoinStats) {
+    bloom_partitioned_mpsm_into(left, right, thread_count, bloom, Vec::new)
+}
 
-    // Reborrow public as mutable so that all threads can share the data
+// partitioned_mpsm with an optional Bloom filter pre-filter. The filter is
+// built over the smaller input and drops the tuples of the larger input
+// without a join partner before they are sorted into public runs or scattered
+// into private partitions. Without a config this is partitioned_mpsm.
+pub fn bloom_partitioned_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, bloom: Option<BloomConfig>, make_sink: F) -> (Vec<S>, JoinStats)
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| bloom_partitioned_mpsm_ctx(left, right, thread_count, bloom, ctx, make_sink))
+}
+
+pub fn bloom_partitioned_mpsm_ctx<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, bloom: Option<BloomConfig>, ctx: &JoinContext, make_sink: F) -> Result<(Vec<S>, JoinStats), JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let Some(config) = bloom else {
+        return Ok((partitioned_mpsm_ctx(left, right, thread_count, ctx, make_sink)?, JoinStats::default()));
+    };
+
+    // Pre-filter
+    let filter_left = left.len() > right.len();
+    let build_start = Instant::now();
+    let filter = BlockedBloomFilter::build_ctx(if filter_left { &right } else { &left }, thread_count, &config, ctx)?;
+    let build_time = build_start.elapsed();
+
+    let probe_start = Instant::now();
+    let (probed, left, mut right) = if filter_left {
+        (left.len(), filter.filter_ctx(left, thread_count, ctx)?, right)
+    } else {
+        (right.len(), left, filter.filter_ctx(right, thread_count, ctx)?)
+    };
+    let probe_time = probe_start.elapsed();
+    let filter_bits = filter.size_bits();
+    drop(filter);
+
+    // Phases 1 and 2
+    let phases_start = Instant::now();
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+    let mut private_chunks = radix_partition_private_ctx(&left, thread_count, ctx)?;
+    let partition_time = phases_start.elapsed();
+
+    // Phases 3 and 4. A worker makes its sink once its chunk is sorted, so
+    // the sink records when phase 3 ended on that worker.
+    let sort_start = Instant::now();
+    let outputs = join_private_partitions(&mut private_chunks, &right, public_chunk_size, ctx, &|| KeyMatches {
+        output: make_sink(),
+        matches: Vec::new(),
+        sorted_after: sort_start.elapsed()
+    })?;
+
+    // The private and the public tuples with a partner, per worker
+    let workers: Vec<(S, usize, usize, Duration)> = thread::scope(|s| {
+        let handles: Vec<_> = outputs.into_iter().zip(&private_chunks).enumerate()
+            .map(|(worker, (output, private_chunk))| affinity::spawn(s, worker, move || {
+                let (private_matched, public_matched) = matched_tuples(private_chunk, output.matches);
+                (output.output, private_matched, public_matched, output.sorted_after)
+            }))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let matched: usize = workers.iter().map(|w| if filter_left { w.1 } else { w.2 }).sum();
+    let passed = if filter_left { left.len() } else { right.len() };
+
+    // The sort and scatter phases cost about the same per tuple, so the
+    // dropped tuples would have cost their share of the measured time.
+    let sort_time = workers.iter().map(|w| w.3).max().unwrap_or_default();
+    // In f64, since the tuple counts exceed u32 on the inputs the filter is
+    // meant for. The estimate saturates instead of overflowing.
+    let dropped_share = (probed - passed) as f64 / (left.len() + right.len()).max(1) as f64;
+    let dropped_time = Duration::try_from_secs_f64((partition_time + sort_time).as_secs_f64() * dropped_share)
+        .unwrap_or(Duration::MAX);
+    let time_saved = dropped_time.saturating_sub(build_time + probe_time);
+
+    let stats = BloomStats {
+        bits_per_key: config.bits_per_key,
+        filter_bits,
+        build_tuples: if filter_left { right.len() } else { left.len() },
+        probed,
+        passed,
+        false_positives: passed - matched,
+        build_time,
+        probe_time,
+        time_saved
+    };
+
+    Ok((workers.into_iter().map(|w| w.0).collect(), JoinStats {bloom: Some(stats)}))
+}
+
+// Sink of bloom_partitioned_mpsm_ctx that passes the joined rows on and
+// counts them per key.
+struct KeyMatches<S> {
+    output: S,
+    // Runs of rows with the same key, in the order the merge produced them
+    matches: Vec<(u64, usize)>,
+    sorted_after: Duration
+}
+
+impl<S: JoinSink<Joined>> JoinSink<Joined> for KeyMatches<S> {
+    #[inline]
+    fn push(&mut self, row: Joined) {
+        match self.matches.last_mut() {
+            Some((key, rows)) if *key == row.key => *rows += 1,
+            _ => self.matches.push((row.key, 1))
+        }
+        self.output.push(row);
+    }
+}
+
+// Counts the tuples of a sorted private chunk and of the public input that
+// have a partner. A key with p private tuples joins each of its public tuples
+// p times, so its public tuples are its rows divided by p.
+fn matched_tuples(private: &[Tuple], mut matches: Vec<(u64, usize)>) -> (usize, usize) {
+    matches.sort_unstable_by_key(|m| m.0);
+
+    let (mut private_matched, mut public_matched) = (0, 0);
+    let mut i = 0;
+    for group in matches.chunk_by(|a, b| a.0 == b.0) {
+        let key = group[0].0;
+        let rows: usize = group.iter().map(|m| m.1).sum();
+        while private[i].key < key { i += 1; }
+        let start = i;
+        while i < private.len() && private[i].key == key { i += 1; }
+        private_matched += i - start;
+        public_matched += rows / (i - start);
+    }
+    (private_matched, public_matched)
+}
+
+// Tuples per sorted run of mway_sort_merge_join: 128 KiB, so a run is sorted
+// within the L2 cache before the runs are merged.
+pub const MWAY_RUN_TUPLES: usize = 8192;
+
+pub fn mway_sort_merge_join(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+    mway_sort_merge_join_into(left, right, thread_count, Vec::new)
+}
+
+// M-way sort-merge join (Balkesen et al., VLDB 2013). Both inputs are radix
+// partitioned on the top log2(thread_count) key bits. Every worker then sorts
+// cache sized runs of its two partitions, merges them with a multiway loser
+// tree into one sorted relation each and joins those with a single linear
+// merge. thread_count must be a power of two of at least 2.
+pub fn mway_sort_merge_join_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| mway_sort_merge_join_ctx(left, right, thread_count, ctx, make_sink))
+}
+
+pub fn mway_sort_merge_join_ctx<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let mut left_partitions = radix_partition_private_ctx(&left, thread_count, ctx)?;
+    drop(left);
+    let mut right_partitions = radix_partition_private_ctx(&right, thread_count, ctx)?;
+    drop(right);
+
+    let make_sink = &make_sink;
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, (lp, rp)) in left_partitions.iter_mut().zip(&mut right_partitions).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                let left_sorted = merge::merge_sort_runs_ctx(lp, MWAY_RUN_TUPLES, ctx)?;
+                let right_sorted = merge::merge_sort_runs_ctx(rp, MWAY_RUN_TUPLES, ctx)?;
+
+                // The sorted right partition is a single public run
+                let mut output = make_sink();
+                merge_join_runs_ctx(&left_sorted, &right_sorted, right_sorted.len().max(1), ctx, &mut output)?;
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Misra-Gries counters per worker for the heavy hitter detection of
+// skew_aware_mpsm. With c counters per worker, every key that makes up more
+// than 1 / (c * thread_count) of the private input is detected.
+pub const HEAVY_HITTER_COUNTERS_PER_WORKER: usize = 4;
+
+// A private key is hot when it alone exceeds this fraction of a partition of
+// fair size, i.e. |R| / (HOT_KEY_SHARE_DIVISOR * thread_count) tuples.
+pub const HOT_KEY_SHARE_DIVISOR: usize = 2;
+
+pub fn skew_aware_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+    skew_aware_mpsm_into(left, right, thread_count, Vec::new)
+}
+
+// P-MPSM for skewed private inputs. Heavy hitters are detected while the
+// radix histograms are built. Tuples with a hot key are kept out of the radix
+// partitions and split evenly over all workers. Each worker sorts its share
+// and joins it against the public tuples of those keys, which every worker
+// reads. No worker then owns the whole cross product of a hot key. Only heavy
+// hitters of the private (left) input are handled: a skewed public input is
+// not split, so the worker owning a hot public key still merges all of it.
+pub fn skew_aware_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| skew_aware_mpsm_ctx(left, right, thread_count, ctx, make_sink))
+}
+
+pub fn skew_aware_mpsm_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+
+    // Phase 1
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    // Phase 2
+    let (mut private_chunks, mut hot) = skew_partition_private_ctx(&left, thread_count, ctx)?;
+
+    // Phases 3 and 4, with each worker also taking its share of the hot tuples
     let public: &[Tuple] = &right;
+    let hot_chunk_size = hot.len().div_ceil(thread_count).max(1);
+    let mut hot_chunks: Vec<&mut [Tuple]> = hot.chunks_mut(hot_chunk_size).collect();
+    hot_chunks.resize_with(private_chunks.len(), Default::default);
 
-    // Sort each private chunk and then merge against a run of public data
-    let mut outputs = Vec::new();
+    let make_sink = &make_sink;
     thread::scope(|s| {
         let mut handles = Vec::new();
-        for private_chunk in &mut private_chunks {
-            handles.push(s.spawn(move || {
+        for (worker, (private_chunk, hot_chunk)) in private_chunks.iter_mut().zip(hot_chunks).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
+                parallel::sort_chunk_ctx(hot_chunk, ctx)?;
+
+                let mut output = make_sink();
+                merge_join_runs_ctx(private_chunk, public, public_chunk_size, ctx, &mut output)?;
+                merge_join_runs_ctx(hot_chunk, public, public_chunk_size, ctx, &mut output)?;
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Phase 2 of skew_aware_mpsm. Returns the radix partitions of the cold private
+// tuples and the tuples with a hot key. Without hot keys this is
+// radix_partition_private and the histograms are not recomputed. Heavy
+// hitters are only looked for in the private input.
+pub fn skew_partition_private(left: &[Tuple], thread_count: usize) -> (Vec<Vec<Tuple>>, Vec<Tuple>) {
+    context::unbounded(|ctx| skew_partition_private_ctx(left, thread_count, ctx))
+}
+
+pub fn skew_partition_private_ctx(left: &[Tuple], thread_count: usize, ctx: &JoinContext) -> Result<(Vec<Vec<Tuple>>, Vec<Tuple>), JoinError> {
+    let (histograms, summaries) = parallel::chunk_histograms_heavy_hitters_ctx(
+        left, thread_count, HEAVY_HITTER_COUNTERS_PER_WORKER * thread_count, ctx)?;
+    let hot_keys = histograms::heavy_hitters(
+        &summaries,
+        parallel::HEAVY_HITTER_SAMPLE_STRIDE as u64,
+        (left.len() / (HOT_KEY_SHARE_DIVISOR * thread_count)) as u64);
+
+    let bits_prefix = thread_count.ilog2();
+    if hot_keys.is_empty() {
+        let prefix_sums = histograms::prefix_sums(&histograms);
+        return Ok((parallel::radix_scatter_ctx(left, thread_count, 64 - bits_prefix, bits_prefix, &prefix_sums, ctx)?, Vec::new()));
+    }
+
+    // Bin thread_count collects the hot tuples
+    let bin = |key: u64| {
+        if hot_keys.binary_search(&key).is_ok() {
+            thread_count
+        } else {
+            parallel::radix_bin(key, 64 - bits_prefix, bits_prefix)
+        }
+    };
+    let histograms = parallel::bin_histograms_ctx(left, thread_count, thread_count + 1, bin, ctx)?;
+    let prefix_sums = histograms::prefix_sums(&histograms);
+    let mut partitions = parallel::bin_scatter_ctx(left, thread_count, thread_count + 1, bin, &prefix_sums, ctx)?;
+
+    let hot = partitions.pop().unwrap();
+    Ok((partitions, hot))
+}
+
+// Number of keys sampled from every sorted public run to pick the splitters
+// of range_partitioned_mpsm.
+pub const SPLITTER_SAMPLES_PER_RUN: usize = 256;
+
+pub fn range_partitioned_mpsm(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize) -> Vec<Vec<Joined>> {
+    range_partitioned_mpsm_into(left, right, thread_count, Vec::new)
+}
+
+pub fn range_partitioned_mpsm_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| range_partitioned_mpsm_ctx(left, right, thread_count, ctx, make_sink))
+}
+
+pub fn range_partitioned_mpsm_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+
+    // Phase 1
+    // Sort the public data among thread_count workers
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    // Phase 2
+    let (mut private_chunks, _) = range_partition_private_ctx(&left, &right, public_chunk_size, thread_count, ctx)?;
+
+    join_private_partitions(&mut private_chunks, &right, public_chunk_size, ctx, &make_sink)
+}
+
+// Phase 2 of range partitioned P-MPSM. Returns the private partitions and the
+// splitters they were cut on.
+pub fn range_partition_private(left: &[Tuple], public: &[Tuple], public_chunk_size: usize, thread_count: usize) -> (Vec<Vec<Tuple>>, Vec<u64>) {
+    context::unbounded(|ctx| range_partition_private_ctx(left, public, public_chunk_size, thread_count, ctx))
+}
+
+pub fn range_partition_private_ctx(left: &[Tuple], public: &[Tuple], public_chunk_size: usize, thread_count: usize, ctx: &JoinContext) -> Result<(Vec<Vec<Tuple>>, Vec<u64>), JoinError> {
+    // Pick splitters from an equi-depth sample of the sorted public runs, so
+    // that every private partition covers about the same share of the public
+    // data regardless of how the keys are distributed over the u64 domain.
+    let runs: Vec<&[Tuple]> = public.chunks(public_chunk_size).collect();
+    let splitters = histograms::equi_depth_splitters(&runs, thread_count, SPLITTER_SAMPLES_PER_RUN);
+    let private_chunks = range_partition_private_on_ctx(left, &splitters, thread_count, ctx)?;
+    Ok((private_chunks, splitters))
+}
+
+// Range partitions the private data on thread_count - 1 splitters.
+pub fn range_partition_private_on(left: &[Tuple], splitters: &[u64], thread_count: usize) -> Vec<Vec<Tuple>> {
+    context::unbounded(|ctx| range_partition_private_on_ctx(left, splitters, thread_count, ctx))
+}
+
+pub fn range_partition_private_on_ctx(left: &[Tuple], splitters: &[u64], thread_count: usize, ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError> {
+    assert!(splitters.len() + 1 == thread_count);
+
+    let histograms = parallel::range_histograms_ctx(left, thread_count, splitters, ctx)?;
+    let prefix_sums = histograms::prefix_sums(&histograms);
+    parallel::range_scatter_ctx(left, thread_count, splitters, &prefix_sums, ctx)
+}
+
+// Inclusive key range covered by each partition of range_partition_private.
+// Repeated splitters leave some partitions without any keys.
+fn splitter_bounds(splitters: &[u64]) -> Vec<Option<(u64, u64)>> {
+    (0..=splitters.len())
+        .map(|i| {
+            let lo = if i == 0 { 0 } else { splitters[i - 1] };
+            let hi = if i == splitters.len() { Some(u64::MAX) } else { splitters[i].checked_sub(1) };
+            hi.filter(|hi| lo <= *hi).map(|hi| (lo, hi))
+        })
+        .collect()
+}
+
+// Merges a sorted private chunk with the window of a sorted public run that
+// lies between the smallest and the largest private key. The public tuples
+// outside the window are never touched, so the cost of phase 4 depends on the
+// matching data rather than on the size of the public input.
+fn merge_join_window<S: JoinSink<Joined>>(private: &[Tuple], public_run: &[Tuple], output: &mut S) {
+    if private.is_empty() {
+        return
+    }
+    // The window holds only private keys, so there is no overlap left to cut
+    let window = key_window(public_run, private[0].key, private[private.len() - 1].key);
+    merge_join_groups(private, window, output);
+}
+
+// Merges a sorted private chunk against every sorted public run. The private
+// chunk is merged in blocks of CHECK_INTERVAL tuples so that ctx is checked
+// between blocks; each block meets the public tuples of its own keys.
+fn merge_join_runs_ctx<S: JoinSink<Joined>>(private: &[Tuple], public: &[Tuple], public_chunk_size: usize, ctx: &JoinContext, output: &mut S) -> Result<(), JoinError> {
+    for public_chunk in public.chunks(public_chunk_size) {
+        for private_block in private.chunks(CHECK_INTERVAL) {
+            ctx.check()?;
+            let mut counted = CountingSink::new(&mut *output);
+            merge_join_window(private_block, public_chunk, &mut counted);
+            if counted.count > 0 {
+                ctx.add_matches(counted.count);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Phases 3 and 4 of P-MPSM. Every worker sorts one private partition and then
+// merges it against the matching window of each sorted public run.
+fn join_private_partitions<S, F>(private_chunks: &mut [Vec<Tuple>], public: &[Tuple], public_chunk_size: usize, ctx: &JoinContext, make_sink: &F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Joined> + Send,
+    F: Fn() -> S + Sync
+{
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, private_chunk) in private_chunks.iter_mut().enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                // Phase 3
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
+
+                // Phase 4
+                let mut output = make_sink();
+                merge_join_runs_ctx(private_chunk, public, public_chunk_size, ctx, &mut output)?;
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Semi or anti join variant of partitioned_mpsm. Returns the tuples of the
+// private (left) input that pass the filter, one vector per partition.
+pub fn partitioned_mpsm_filter(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, filter: JoinFilter) -> Vec<Vec<Tuple>> {
+    partitioned_mpsm_filter_into(left, right, thread_count, filter, Vec::new)
+}
+
+pub fn partitioned_mpsm_filter_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, filter: JoinFilter, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<Tuple> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| partitioned_mpsm_filter_ctx(left, right, thread_count, filter, ctx, make_sink))
+}
+
+// The tuples that pass the filter are reported to ctx as matches.
+pub fn partitioned_mpsm_filter_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, filter: JoinFilter, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<Tuple> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    let mut private_chunks = radix_partition_private_ctx(&left, thread_count, ctx)?;
+    let public: &[Tuple] = &right;
+    let make_sink = &make_sink;
+
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, private_chunk) in private_chunks.iter_mut().enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
                 // Phase 3
-                private_chunk.sort_by_key(|t| t.key);
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
 
                 // Phase 4
-                let mut output = Vec::new();
+                // A private tuple may find its match in any public run, so the
+                // matches are only marked here and filtered after the last run.
+                let mut matched = vec![false; private_chunk.len()];
                 for public_chunk in public.chunks(public_chunk_size) {
-                    merge_join_sorted(private_chunk, public_chunk, &mut output);
+                    for (block_index, block) in private_chunk.chunks(CHECK_INTERVAL).enumerate() {
+                        ctx.check()?;
+                        let offset = block_index * CHECK_INTERVAL;
+                        let window = key_window(public_chunk, block[0].key, block[block.len() - 1].key);
+                        for_each_match_group(block, window, |l_range, _| matched[offset + l_range.start..offset + l_range.end].fill(true));
+                    }
+                }
+
+                let mut output = make_sink();
+                let mut kept = 0;
+                for (t, m) in private_chunk.iter().zip(matched) {
+                    if filter.keeps(m) {
+                        output.push(*t);
+                        kept += 1;
+                    }
+                }
+                if kept > 0 {
+                    ctx.add_matches(kept);
                 }
-                output
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Outer variant of basic_mpsm. A public tuple can be matched by any private
+// chunk, so the workers record matches in a shared bitmap over the public data
+// and the unmatched public tuples are collected once every worker is done.
+pub fn basic_mpsm_outer(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType) -> Vec<Vec<OuterJoined>> {
+    basic_mpsm_outer_into(left, right, thread_count, join_type, Vec::new)
+}
+
+pub fn basic_mpsm_outer_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| basic_mpsm_outer_ctx(left, right, thread_count, join_type, ctx, make_sink))
+}
+
+// Every output row of an outer join, matched or not, is reported to ctx as a
+// match.
+pub fn basic_mpsm_outer_ctx<S, F>(mut left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, join_type: JoinType, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    let public: &[Tuple] = &right;
+    let private_chunk_size = left.len().div_ceil(thread_count).max(1);
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    let public_matched: Vec<AtomicBool> = if join_type.keeps_right() {
+        (0..public.len()).map(|_| AtomicBool::new(false)).collect()
+    } else {
+        Vec::new()
+    };
+    let public_matched: &[AtomicBool] = &public_matched;
+    let make_sink = &make_sink;
+
+    let mut outputs: Vec<S> = thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, private_chunk) in left.chunks_mut(private_chunk_size).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
+
+                let mut output = make_sink();
+                merge_join_private_outer(private_chunk, public, public_chunk_size, PublicOwnership::Shared(public_matched), join_type, ctx, &mut output)?;
+                Ok(output)
             }));
         }
-        for h in handles {
-            outputs.push(h.join().unwrap());
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Result<_, JoinError>>()
+    })?;
+
+    if join_type.keeps_right() {
+        // Sink i reports the unmatched tuples of public run i. There can be
+        // more public runs than private chunks when the private input is small.
+        let run_count = public.len().div_ceil(public_chunk_size);
+        while outputs.len() < run_count {
+            outputs.push(make_sink());
         }
-    });
+        thread::scope(|s| {
+            let runs = public.chunks(public_chunk_size).zip(public_matched.chunks(public_chunk_size));
+            let handles: Vec<_> = outputs.iter_mut().zip(runs).enumerate()
+                .map(|(worker, (output, (run, matched)))| affinity::spawn(s, worker, move || {
+                    for (block, block_matched) in run.chunks(CHECK_INTERVAL).zip(matched.chunks(CHECK_INTERVAL)) {
+                        ctx.check()?;
+                        let mut counted = CountingSink::new(&mut *output);
+                        for (t, m) in block.iter().zip(block_matched) {
+                            if !m.load(atomic::Ordering::Relaxed) {
+                                counted.push(OuterJoined::right_only(t));
+                            }
+                        }
+                        if counted.count > 0 {
+                            ctx.add_matches(counted.count);
+                        }
+                    }
+                    Ok(())
+                }))
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })?;
+    }
 
-    outputs
+    Ok(outputs)
+}
+
+// Outer variant of partitioned_mpsm.
+pub fn partitioned_mpsm_outer(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType) -> Vec<Vec<OuterJoined>> {
+    partitioned_mpsm_outer_into(left, right, thread_count, join_type, Vec::new)
+}
+
+pub fn partitioned_mpsm_outer_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| partitioned_mpsm_outer_ctx(left, right, thread_count, join_type, ctx, make_sink))
+}
+
+pub fn partitioned_mpsm_outer_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, join_type: JoinType, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    let mut private_chunks = radix_partition_private_ctx(&left, thread_count, ctx)?;
+    let bounds = radix_partition_bounds(thread_count);
+
+    join_private_partitions_outer(&mut private_chunks, &bounds, &right, public_chunk_size, join_type, ctx, &make_sink)
+}
+
+// Outer variant of range_partitioned_mpsm.
+pub fn range_partitioned_mpsm_outer(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType) -> Vec<Vec<OuterJoined>> {
+    range_partitioned_mpsm_outer_into(left, right, thread_count, join_type, Vec::new)
+}
+
+pub fn range_partitioned_mpsm_outer_into<S, F>(left: Vec<Tuple>, right: Vec<Tuple>, thread_count: usize, join_type: JoinType, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    context::unbounded(|ctx| range_partitioned_mpsm_outer_ctx(left, right, thread_count, join_type, ctx, make_sink))
+}
+
+pub fn range_partitioned_mpsm_outer_ctx<S, F>(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, join_type: JoinType, ctx: &JoinContext, make_sink: F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+    parallel::sort_runs_parallel_ctx(&mut right, thread_count, ctx)?;
+
+    let (mut private_chunks, splitters) = range_partition_private_ctx(&left, &right, public_chunk_size, thread_count, ctx)?;
+    let bounds = splitter_bounds(&splitters);
+
+    join_private_partitions_outer(&mut private_chunks, &bounds, &right, public_chunk_size, join_type, ctx, &make_sink)
+}
+
+// Decides which worker reports an unmatched public tuple.
+enum PublicOwnership<'a> {
+    // The worker owns every public tuple in this key range. No other private
+    // partition holds those keys, so the worker alone knows if they matched.
+    Range(Option<(u64, u64)>),
+    // Any worker may match any public tuple, so matches are recorded in a
+    // bitmap over the public data and reported after all workers are done.
+    Shared(&'a [AtomicBool])
+}
+
+// Phases 3 and 4 of P-MPSM for outer joins. Every partition reports the public
+// tuples in its own key range that it did not match.
+fn join_private_partitions_outer<S, F>(private_chunks: &mut [Vec<Tuple>], bounds: &[Option<(u64, u64)>], public: &[Tuple], public_chunk_size: usize, join_type: JoinType, ctx: &JoinContext, make_sink: &F) -> Result<Vec<S>, JoinError>
+where
+    S: JoinSink<OuterJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(private_chunks.len() == bounds.len());
+
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, (private_chunk, bound)) in private_chunks.iter_mut().zip(bounds).enumerate() {
+            handles.push(affinity::spawn(s, worker, move || {
+                // Phase 3
+                parallel::sort_chunk_ctx(private_chunk, ctx)?;
+
+                // Phase 4
+                let mut output = make_sink();
+                merge_join_private_outer(private_chunk, public, public_chunk_size, PublicOwnership::Range(*bound), join_type, ctx, &mut output)?;
+                Ok(output)
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Merges one sorted private chunk against every sorted public run, reporting
+// unmatched private tuples and, depending on the ownership, unmatched public
+// tuples. Within a run the private chunk is merged in blocks of
+// CHECK_INTERVAL tuples, so that ctx is checked between blocks.
+fn merge_join_private_outer<S: JoinSink<OuterJoined>>(private: &[Tuple], public: &[Tuple], public_chunk_size: usize, ownership: PublicOwnership, join_type: JoinType, ctx: &JoinContext, output: &mut S) -> Result<(), JoinError> {
+    let mut private_matched = vec![false; private.len()];
+    let mut output = CountingSink::new(output);
+    let mut reported = 0;
+
+    for (run_index, run) in public.chunks(public_chunk_size).enumerate() {
+        let window = match &ownership {
+            PublicOwnership::Range(Some((lo, hi))) => key_window_range(run, *lo, *hi),
+            PublicOwnership::Range(None) => 0..0,
+            PublicOwnership::Shared(_) if private.is_empty() => 0..0,
+            PublicOwnership::Shared(_) => key_window_range(run, private[0].key, private[private.len() - 1].key)
+        };
+        let window_start = window.start;
+        let window = &run[window];
+        let mut window_matched = vec![false; if join_type.keeps_right() { window.len() } else { 0 }];
+
+        for (block_index, block) in private.chunks(CHECK_INTERVAL).enumerate() {
+            ctx.check()?;
+            let offset = block_index * CHECK_INTERVAL;
+            let block_window = key_window_range(window, block[0].key, block[block.len() - 1].key);
+            let block_start = block_window.start;
+            let block_window = &window[block_window];
+            for_each_match_group(block, block_window, |l_range, r_range| {
+                private_matched[offset + l_range.start..offset + l_range.end].fill(true);
+                if join_type.keeps_right() {
+                    window_matched[block_start + r_range.start..block_start + r_range.end].fill(true);
+                }
+                for lt in &block[l_range] {
+                    for rt in &block_window[r_range.clone()] {
+                        output.push(OuterJoined::new(lt.key, Some(lt.payload), Some(rt.payload)));
+                    }
+                }
+            });
+        }
+
+        if join_type.keeps_right() {
+            match &ownership {
+                PublicOwnership::Range(_) => {
+                    for (t, m) in window.iter().zip(&window_matched) {
+                        if !m {
+                            output.push(OuterJoined::right_only(t));
+                        }
+                    }
+                }
+                PublicOwnership::Shared(public_matched) => {
+                    let offset = run_index * public_chunk_size + window_start;
+                    for (i, _) in window_matched.iter().enumerate().filter(|(_, m)| **m) {
+                        public_matched[offset + i].store(true, atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        if output.count > reported {
+            ctx.add_matches(output.count - reported);
+            reported = output.count;
+        }
+    }
+
+    if join_type.keeps_left() {
+        ctx.check()?;
+        for (t, m) in private.iter().zip(&private_matched) {
+            if !m {
+                output.push(OuterJoined::left_only(t));
+            }
+        }
+        if output.count > reported {
+            ctx.add_matches(output.count - reported);
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use rand::{rngs::StdRng, SeedableRng};
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-    use crate::infrastructure;
+    use crate::{infrastructure, sink::{self, AggregateSink, CountSink}};
 
     use super::*;
 
@@ -225,6 +1285,31 @@ mod test {
         assert!(infrastructure::table_eq(&nl_output, &sm_output))
     }
 
+    #[test]
+    fn compare_galloping_merge_join_asymmetric() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = infrastructure::gen_tables(50000, 0.7, &mut rng);
+
+        for small_len in [0, 1, 10, 200, 5000] {
+            let small = fact[..small_len].to_vec();
+            let expected = basic_sort_merge_join(small.clone(), dimension.clone());
+
+            let output = galloping_sort_merge_join(small.clone(), dimension.clone());
+            assert!(infrastructure::table_eq(&expected, &output), "{small_len} left");
+
+            let expected = basic_sort_merge_join(fact.clone(), small[..small_len.min(100)].to_vec());
+            let output = galloping_sort_merge_join(fact.clone(), small[..small_len.min(100)].to_vec());
+            assert!(infrastructure::table_eq(&expected, &output), "{small_len} right");
+        }
+
+        // Alternating long gaps on both sides and runs of duplicates
+        let left: Vec<Tuple> = (0..3000).map(|k| Tuple::new((k / 100) * 1000 + k % 100, k)).collect();
+        let right: Vec<Tuple> = (0..3000).map(|k| Tuple::new(k * 7 % 30000, k)).collect();
+        let expected = nested_loop_join(&left, &right);
+        let output = galloping_sort_merge_join(left, right);
+        assert!(infrastructure::table_eq(&expected, &output));
+    }
+
     #[test]
     fn compare_basic_mpsm_nested_loop() {
         let mut rng = StdRng::seed_from_u64(101);
@@ -246,4 +1331,334 @@ mod test {
 
         assert!(infrastructure::table_eq(&nl_output, &mpsm_output));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn compare_range_partitioned_mpsm_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+
+        let nl_output = nested_loop_join(&lt, &rt);
+        for thread_count in [1, 3, 4] {
+            let mpsm_output = range_partitioned_mpsm(lt.clone(), rt.clone(), thread_count)
+                .into_iter().flatten().collect::<Vec<Joined>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output));
+        }
+    }
+
+    #[test]
+    fn compare_mway_sort_merge_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        // Large enough that every partition is merged from several runs
+        let (lt, rt) = infrastructure::gen_tables(40000, 0.7, &mut rng);
+
+        let expected = basic_sort_merge_join(lt.clone(), rt.clone());
+        for thread_count in [2, 4] {
+            let output = mway_sort_merge_join(lt.clone(), rt.clone(), thread_count)
+                .into_iter().flatten().collect::<Vec<Joined>>();
+            assert!(infrastructure::table_eq(&expected, &output));
+        }
+
+        let (lt, rt) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+        let nl_output = nested_loop_join(&lt, &rt);
+        let output = mway_sort_merge_join(lt, rt, 4).into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &output));
+    }
+
+    #[test]
+    fn bloom_partitioned_mpsm_selective_join() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+        // Random keys that miss the dimension table make up 90% of the large side
+        let mut large = infrastructure::gen_table(9 * fact.len(), &mut rng);
+        large.extend(&fact);
+        large.shuffle(&mut rng);
+
+        let expected = basic_sort_merge_join(large.clone(), dimension.clone());
+        assert_eq!(expected.len(), fact.len());
+
+        let (outputs, stats) = bloom_partitioned_mpsm(large.clone(), dimension.clone(), 4, Some(BloomConfig::default()));
+        let output = outputs.into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+
+        let bloom = stats.bloom.unwrap();
+        assert_eq!(bloom.build_tuples, dimension.len());
+        assert_eq!(bloom.probed, large.len());
+        assert_eq!(bloom.passed - bloom.false_positives, fact.len());
+        assert!(bloom.false_positive_rate() < 0.03, "{}", bloom.false_positive_rate());
+        assert!(bloom.dropped() > 8 * fact.len());
+
+        // The filter is built over whichever side is smaller
+        let (outputs, stats) = bloom_partitioned_mpsm(dimension.clone(), large.clone(), 4, Some(BloomConfig::new(4)));
+        let expected = basic_sort_merge_join(dimension.clone(), large.clone());
+        let output = outputs.into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+        let bloom = stats.bloom.unwrap();
+        assert_eq!(bloom.probed, large.len());
+        assert_eq!(bloom.passed - bloom.false_positives, fact.len());
+
+        let (_, stats) = bloom_partitioned_mpsm(large, dimension, 4, None);
+        assert_eq!(stats.bloom, None);
+    }
+
+    #[test]
+    fn compare_skew_aware_mpsm_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        for s in [0.0, 1.2] {
+            let (lt, rt) = infrastructure::gen_zipf_tables(2000, 20000, s, &mut rng);
+
+            let nl_output = nested_loop_join(&lt, &rt);
+            for thread_count in [2, 4] {
+                let mpsm_output = skew_aware_mpsm(lt.clone(), rt.clone(), thread_count)
+                    .into_iter().flatten().collect::<Vec<Joined>>();
+                assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "s = {s}");
+            }
+        }
+    }
+
+    #[test]
+    fn skew_aware_mpsm_spreads_hot_keys() {
+        let mut rng = StdRng::seed_from_u64(101);
+        // The most popular key makes up about a quarter of the fact table
+        let (lt, rt) = infrastructure::gen_zipf_tables(2000, 40000, 1.2, &mut rng);
+        let rt: Vec<Tuple> = rt.into_iter().flat_map(|t| [t, Tuple::new(t.key, !t.payload)]).collect();
+
+        let (_, hot) = skew_partition_private(&lt, 4);
+        assert!(hot.len() > lt.len() / 8);
+
+        let largest = |outputs: Vec<Vec<Joined>>| outputs.iter().map(|o| o.len()).max().unwrap();
+        let total = 2 * lt.len();
+        let skewed = largest(partitioned_mpsm(lt.clone(), rt.clone(), 4));
+        let spread = largest(skew_aware_mpsm(lt, rt, 4));
+        assert!(spread < skewed, "{spread} >= {skewed}");
+        assert!(spread < total * 2 / 5, "{spread} of {total}");
+    }
+
+    #[test]
+    fn range_partitioned_mpsm_dense_keys() {
+        // Keys in 0..n all share their top bits, so radix partitioning on the
+        // top bits would send every tuple to the first partition.
+        let lt: Vec<Tuple> = (0..20000).map(|k| Tuple::new(k % 5000, k)).collect();
+        let rt: Vec<Tuple> = (0..5000).map(|k| Tuple::new(k, k)).collect();
+
+        let nl_output = nested_loop_join(&lt, &rt);
+        let outputs = range_partitioned_mpsm(lt, rt, 4);
+        for output in &outputs {
+            assert!(output.len() > 4000 && output.len() < 6000, "unbalanced partition of {}", output.len());
+        }
+
+        let mpsm_output = outputs.into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&nl_output, &mpsm_output));
+    }
+
+    #[test]
+    fn key_window_test() {
+        let run = vec![
+            Tuple::new(2, 0),
+            Tuple::new(4, 0),
+            Tuple::new(4, 1),
+            Tuple::new(7, 0),
+            Tuple::new(9, 0)
+        ];
+        assert_eq!(key_window(&run, 4, 7), &run[1..4]);
+        assert_eq!(key_window(&run, 3, 8), &run[1..4]);
+        assert_eq!(key_window(&run, 0, 1), &run[0..0]);
+        assert_eq!(key_window(&run, 10, 12), &run[5..5]);
+        assert_eq!(key_window(&run, 0, u64::MAX), &run[..]);
+    }
+
+    #[test]
+    fn merge_join_sorted_trims_tails() {
+        let left = vec![Tuple::new(1, 1), Tuple::new(5, 2), Tuple::new(5, 3), Tuple::new(20, 4)];
+        let right = vec![Tuple::new(0, 5), Tuple::new(5, 6), Tuple::new(6, 7), Tuple::new(u64::MAX, 8)];
+
+        let mut output = Vec::new();
+        merge_join_sorted(&left, &right, &mut output);
+        assert_eq!(output, vec![Joined::new(5, 2, 6), Joined::new(5, 3, 6)]);
+    }
+
+    // Fact table with a few keys that have no dimension tuple, so that every
+    // outer join type has unmatched tuples on both sides.
+    fn outer_join_tables() -> (Vec<Tuple>, Vec<Tuple>) {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (mut lt, rt) = infrastructure::gen_tables(4000, 0.5, &mut rng);
+        lt.extend(infrastructure::gen_table(500, &mut rng));
+        (lt, rt)
+    }
+
+    #[test]
+    fn merge_join_sorted_outer_test() {
+        let left = vec![Tuple::new(1, 10), Tuple::new(3, 30), Tuple::new(3, 31), Tuple::new(6, 60)];
+        let right = vec![Tuple::new(2, 20), Tuple::new(3, 32), Tuple::new(7, 70)];
+
+        let mut output = Vec::new();
+        merge_join_sorted_outer(&left, &right, JoinType::FullOuter, &mut output);
+        assert_eq!(output, vec![
+            OuterJoined::new(1, Some(10), None),
+            OuterJoined::new(2, None, Some(20)),
+            OuterJoined::new(3, Some(30), Some(32)),
+            OuterJoined::new(3, Some(31), Some(32)),
+            OuterJoined::new(6, Some(60), None),
+            OuterJoined::new(7, None, Some(70))
+        ]);
+
+        let mut output = Vec::new();
+        merge_join_sorted_outer(&left, &right, JoinType::RightOuter, &mut output);
+        assert_eq!(output.len(), 4);
+        assert!(output.iter().all(|o| o.right_payload.is_some()));
+    }
+
+    #[test]
+    fn splitter_bounds_test() {
+        assert_eq!(splitter_bounds(&[10, 10, 20]), vec![
+            Some((0, 9)),
+            None,
+            Some((10, 19)),
+            Some((20, u64::MAX))
+        ]);
+        assert_eq!(splitter_bounds(&[0]), vec![None, Some((0, u64::MAX))]);
+        assert_eq!(radix_partition_bounds(2), vec![
+            Some((0, (1 << 63) - 1)),
+            Some((1 << 63, u64::MAX))
+        ]);
+    }
+
+    #[test]
+    fn compare_outer_joins_nested_loop() {
+        let (lt, rt) = outer_join_tables();
+
+        for join_type in [JoinType::Inner, JoinType::LeftOuter, JoinType::RightOuter, JoinType::FullOuter] {
+            let nl_output = nested_loop_join_outer(&lt, &rt, join_type);
+
+            let sm_output = sort_merge_join_outer(lt.clone(), rt.clone(), join_type);
+            assert!(infrastructure::table_eq(&nl_output, &sm_output), "sort merge {join_type:?}");
+
+            let mpsm_output = basic_mpsm_outer(lt.clone(), rt.clone(), 3, join_type)
+                .into_iter().flatten().collect::<Vec<OuterJoined>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "basic mpsm {join_type:?}");
+
+            let mpsm_output = partitioned_mpsm_outer(lt.clone(), rt.clone(), 4, join_type)
+                .into_iter().flatten().collect::<Vec<OuterJoined>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "partitioned mpsm {join_type:?}");
+
+            let mpsm_output = range_partitioned_mpsm_outer(lt.clone(), rt.clone(), 4, join_type)
+                .into_iter().flatten().collect::<Vec<OuterJoined>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "range partitioned mpsm {join_type:?}");
+        }
+    }
+
+    #[test]
+    fn inner_outer_join_matches_inner_join() {
+        let (lt, rt) = outer_join_tables();
+
+        let inner = basic_sort_merge_join(lt.clone(), rt.clone())
+            .into_iter().map(OuterJoined::from).collect::<Vec<OuterJoined>>();
+        let outer = sort_merge_join_outer(lt, rt, JoinType::Inner);
+        assert!(infrastructure::table_eq(&inner, &outer));
+    }
+
+    #[test]
+    fn merge_join_sorted_filter_test() {
+        let left = vec![Tuple::new(1, 10), Tuple::new(3, 30), Tuple::new(3, 31), Tuple::new(6, 60)];
+        let right = vec![Tuple::new(3, 32), Tuple::new(3, 33), Tuple::new(3, 34), Tuple::new(7, 70)];
+
+        let mut semi = Vec::new();
+        merge_join_sorted_filter(&left, &right, JoinFilter::Semi, &mut semi);
+        assert_eq!(semi, vec![Tuple::new(3, 30), Tuple::new(3, 31)]);
+
+        let mut anti = Vec::new();
+        merge_join_sorted_filter(&left, &right, JoinFilter::Anti, &mut anti);
+        assert_eq!(anti, vec![Tuple::new(1, 10), Tuple::new(6, 60)]);
+    }
+
+    #[test]
+    fn compare_filter_joins_nested_loop() {
+        let (lt, rt) = outer_join_tables();
+
+        for filter in [JoinFilter::Semi, JoinFilter::Anti] {
+            // Filter the fact table by the dimension table and the other way round
+            for (l, r) in [(&lt, &rt), (&rt, &lt)] {
+                let nl_output = nested_loop_join_filter(l, r, filter);
+
+                let sm_output = sort_merge_join_filter(l.clone(), r.clone(), filter);
+                assert!(infrastructure::table_eq(&nl_output, &sm_output), "sort merge {filter:?}");
+
+                let mpsm_output = partitioned_mpsm_filter(l.clone(), r.clone(), 4, filter)
+                    .into_iter().flatten().collect::<Vec<Tuple>>();
+                assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "partitioned mpsm {filter:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn mpsm_sinks_match_materialized_output() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+
+        let mut expected = AggregateSink::default();
+        for j in nested_loop_join(&lt, &rt) {
+            expected.push(j);
+        }
+
+        let counts = basic_mpsm_into(lt.clone(), rt.clone(), 4, CountSink::default);
+        assert_eq!(sink::combine_all(counts).count, expected.left.count);
+
+        let aggregates = partitioned_mpsm_into(lt.clone(), rt.clone(), 4, AggregateSink::default);
+        assert_eq!(sink::combine_all(aggregates), expected);
+
+        let aggregates = range_partitioned_mpsm_into(lt.clone(), rt.clone(), 4, AggregateSink::default);
+        assert_eq!(sink::combine_all(aggregates), expected);
+
+        let aggregates = skew_aware_mpsm_into(lt, rt, 4, AggregateSink::default);
+        assert_eq!(sink::combine_all(aggregates), expected);
+    }
+
+    #[test]
+    fn matched_tuples_across_runs() {
+        // Key 3 has two private tuples and meets three public tuples in two
+        // runs; key 5 has no partner.
+        let private = [Tuple::new(1, 0), Tuple::new(3, 0), Tuple::new(3, 1), Tuple::new(5, 0)];
+        let mut sink = KeyMatches {output: Vec::new(), matches: Vec::new(), sorted_after: Duration::ZERO};
+        merge_join_sorted(&private, &[Tuple::new(1, 0), Tuple::new(3, 0), Tuple::new(3, 1)], &mut sink);
+        merge_join_sorted(&private, &[Tuple::new(2, 0), Tuple::new(3, 2)], &mut sink);
+        assert_eq!(sink.output.len(), 7);
+        assert_eq!(matched_tuples(&private, sink.matches), (3, 4));
+    }
+
+    #[test]
+    fn mpsm_variants_merge_large_partitions_in_blocks() {
+        // Private partitions of well over CHECK_INTERVAL tuples, with every
+        // key repeated often enough to span the blocks they are merged in
+        let mut rng = StdRng::seed_from_u64(101);
+        let keys = infrastructure::gen_keys(1000, &mut rng);
+        let left: Vec<Tuple> = (0..200000).map(|i| Tuple::new(keys[i % keys.len()], i as u64)).collect();
+        let mut right = infrastructure::gen_table(300000, &mut rng);
+        for (i, t) in right.iter_mut().take(1000).enumerate() {
+            t.key = keys[i % (keys.len() / 2)];
+        }
+        right.shuffle(&mut rng);
+
+        let expected = basic_sort_merge_join(left.clone(), right.clone());
+        let output = mway_sort_merge_join(left.clone(), right.clone(), 2).into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+
+        // The filter drops the large right side; the false positives only
+        // come out right if every matching right tuple is counted once.
+        let (outputs, stats) = bloom_partitioned_mpsm(left.clone(), right.clone(), 2, Some(BloomConfig::default()));
+        let output = outputs.into_iter().flatten().collect::<Vec<Joined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+        let bloom = stats.bloom.unwrap();
+        assert_eq!(bloom.passed - bloom.false_positives, 1000);
+
+        let expected = sort_merge_join_outer(left.clone(), right.clone(), JoinType::FullOuter);
+        let output = partitioned_mpsm_outer(left.clone(), right.clone(), 2, JoinType::FullOuter).into_iter().flatten().collect::<Vec<OuterJoined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+        let output = basic_mpsm_outer(left.clone(), right.clone(), 2, JoinType::FullOuter).into_iter().flatten().collect::<Vec<OuterJoined>>();
+        assert!(infrastructure::table_eq(&expected, &output));
+
+        for filter in [JoinFilter::Semi, JoinFilter::Anti] {
+            let expected = sort_merge_join_filter(left.clone(), right.clone(), filter);
+            let output = partitioned_mpsm_filter(left.clone(), right.clone(), 2, filter).into_iter().flatten().collect::<Vec<Tuple>>();
+            assert!(infrastructure::table_eq(&expected, &output));
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::thread;
+
+use crate::{affinity, algorithms::{JoinAlgorithm, JoinConfig, OutputMode}, sink::{CombineSink, JoinSink}, stream::Side, tuples::{Joined, OuterJoined, RowIdOuterPair, RowIdPair, Tuple}};
+
+// Late materialization. Only the key column of a wide table takes part in
+// the join: every join runs on (key, row_id) tuples, so the payloads of its
+// output rows are row ids. A gather step afterwards fetches the columns the
+// query needs for the matching rows. Early materialization would instead
+// carry the payload columns through partitioning, sorting and merging.
+
+// Join input for a key column: the tuple of row i is (keys[i], i).
+pub fn key_row_ids(keys: &[u64]) -> Vec<Tuple> {
+    keys.iter().enumerate().map(|(i, k)| Tuple::new(*k, i as u64)).collect()
+}
+
+// The row id mode of the joins in join.rs: passed as the sink of any *_into
+// join over key_row_ids inputs, e.g.
+//   join::partitioned_mpsm_into(left, right, 4, || RowIdSink(Vec::new()))
+// it keeps the row ids of every output row and nothing else. Inner joins yield
+// RowIdPairs, outer joins RowIdOuterPairs and semi and anti joins the row ids
+// of the left rows that pass.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RowIdSink<S>(pub S);
+
+impl<S> RowIdSink<S> {
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl<S: JoinSink<RowIdPair>> JoinSink<Joined> for RowIdSink<S> {
+    #[inline]
+    fn push(&mut self, row: Joined) {
+        self.0.push(RowIdPair::from(row));
+    }
+}
+
+impl<S: JoinSink<RowIdOuterPair>> JoinSink<OuterJoined> for RowIdSink<S> {
+    #[inline]
+    fn push(&mut self, row: OuterJoined) {
+        self.0.push(RowIdOuterPair::from(row));
+    }
+}
+
+impl<S: JoinSink<u64>> JoinSink<Tuple> for RowIdSink<S> {
+    #[inline]
+    fn push(&mut self, row: Tuple) {
+        self.0.push(row.payload);
+    }
+}
+
+impl<S: CombineSink> CombineSink for RowIdSink<S> {
+    fn combine(&mut self, other: Self) {
+        self.0.combine(other.0);
+    }
+}
+
+// Joins two key columns with a registered join in OutputMode::RowIds. The
+// output mode of config is ignored.
+pub fn row_id_join(algorithm: &dyn JoinAlgorithm, left_keys: &[u64], right_keys: &[u64], config: &JoinConfig) -> Vec<RowIdPair> {
+    let config = JoinConfig::new(config.thread_count, OutputMode::RowIds);
+    algorithm.join(key_row_ids(left_keys), key_row_ids(right_keys), &config)
+        .into_row_ids()
+        .expect("row id joins return row ids")
+}
+
+// Fetches the values of one column of the given side for every row id pair,
+// in the order of the pairs, with thread_count workers.
+pub fn gather_column<T>(pairs: &[RowIdPair], side: Side, column: &[T], thread_count: usize) -> Vec<T>
+where
+    T: Clone + Send + Sync
+{
+    assert!(thread_count > 0);
+
+    let chunk_size = pairs.len().div_ceil(thread_count).max(1);
+    thread::scope(|s| {
+        let handles: Vec<_> = pairs.chunks(chunk_size).enumerate()
+            .map(|(worker, chunk)| affinity::spawn(s, worker, move || {
+                chunk.iter()
+                    .map(|p| match side {
+                        Side::Left => column[p.left_row as usize].clone(),
+                        Side::Right => column[p.right_row as usize].clone()
+                    })
+                    .collect::<Vec<T>>()
+            }))
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{algorithms::Registry, infrastructure, join::{self, JoinFilter, JoinType}};
+
+    use super::*;
+
+    // A wide row whose key is one of many columns
+    #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+    struct Row {
+        key: u64,
+        columns: [u64; 6]
+    }
+
+    fn gen_rows<R: Rng>(keys: &[u64], rng: &mut R) -> Vec<Row> {
+        keys.iter().map(|k| Row {key: *k, columns: rng.random()}).collect()
+    }
+
+    #[test]
+    fn late_materialized_join_matches_early() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(3000, 0.7, &mut rng);
+        let left = gen_rows(&lt.iter().map(|t| t.key).collect::<Vec<u64>>(), &mut rng);
+        let right = gen_rows(&rt.iter().map(|t| t.key).collect::<Vec<u64>>(), &mut rng);
+
+        let mut expected = Vec::new();
+        for l in &left {
+            for r in right.iter().filter(|r| r.key == l.key) {
+                expected.push((*l, *r));
+            }
+        }
+
+        let left_keys: Vec<u64> = left.iter().map(|r| r.key).collect();
+        let right_keys: Vec<u64> = right.iter().map(|r| r.key).collect();
+        let pairs: Vec<RowIdPair> = join::partitioned_mpsm_into(key_row_ids(&left_keys), key_row_ids(&right_keys), 4, || RowIdSink(Vec::new()))
+            .into_iter().flat_map(RowIdSink::into_inner).collect();
+
+        let gathered_left = gather_column(&pairs, Side::Left, &left, 3);
+        let gathered_right = gather_column(&pairs, Side::Right, &right, 3);
+        let output: Vec<(Row, Row)> = gathered_left.into_iter().zip(gathered_right).collect();
+        assert!(infrastructure::table_eq(&expected, &output));
+    }
+
+    #[test]
+    fn row_id_join_registry() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+        let left_keys: Vec<u64> = lt.iter().map(|t| t.key).collect();
+        let right_keys: Vec<u64> = rt.iter().map(|t| t.key).collect();
+
+        let expected: Vec<RowIdPair> = join::nested_loop_join(&key_row_ids(&left_keys), &key_row_ids(&right_keys))
+            .into_iter().map(RowIdPair::from).collect();
+
+        let config = JoinConfig::new(3, OutputMode::Count);
+        for algorithm in Registry::with_defaults().algorithms() {
+            let output = row_id_join(algorithm, &left_keys, &right_keys, &config);
+            assert!(infrastructure::table_eq(&expected, &output), "{}", algorithm.name());
+        }
+    }
+
+    #[test]
+    fn row_id_outer_and_filter_joins() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+        let left = key_row_ids(&lt.iter().map(|t| t.key).collect::<Vec<u64>>());
+        let right = key_row_ids(&rt.iter().map(|t| t.key).collect::<Vec<u64>>());
+
+        for join_type in [JoinType::LeftOuter, JoinType::RightOuter, JoinType::FullOuter] {
+            let expected: Vec<RowIdOuterPair> = join::nested_loop_join_outer(&left, &right, join_type)
+                .into_iter().map(RowIdOuterPair::from).collect();
+            let output: Vec<RowIdOuterPair> = join::partitioned_mpsm_outer_into(left.clone(), right.clone(), 4, join_type, || RowIdSink(Vec::new()))
+                .into_iter().flat_map(RowIdSink::into_inner).collect();
+            assert!(infrastructure::table_eq(&expected, &output), "{join_type:?}");
+        }
+
+        for filter in [JoinFilter::Semi, JoinFilter::Anti] {
+            let expected: Vec<u64> = join::nested_loop_join_filter(&left, &right, filter).iter().map(|t| t.payload).collect();
+            let output: Vec<u64> = join::partitioned_mpsm_filter_into(left.clone(), right.clone(), 4, filter, || RowIdSink(Vec::new()))
+                .into_iter().flat_map(RowIdSink::into_inner).collect();
+            assert!(infrastructure::table_eq(&expected, &output), "{filter:?}");
+        }
+    }
+}
@@ -9,3 +9,19 @@ pub mod parallel;
 pub mod histograms;
 pub mod datasets;
 pub mod ideal;
+pub mod algorithms;
+pub mod hash_join;
+pub mod range_join;
+pub mod asof_join;
+pub mod stream;
+pub mod sink;
+pub mod disk;
+pub mod affinity;
+pub mod planner;
+pub mod bloom;
+pub mod index_join;
+pub mod star_join;
+pub mod triejoin;
+pub mod group_join;
+pub mod late;
+pub mod context;
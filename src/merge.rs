@@ -2,16 +2,39 @@
 
 use std::{iter::Peekable, slice::Iter};
 
-pub struct Merge<'a> {
-    pub dataset: Vec<Peekable<Iter<'a, i64>>>,
+use crate::{context::{self, JoinContext, JoinError, CHECK_INTERVAL}, tuples::Tuple};
+
+// Values the loser tree can merge, ordered by their key.
+pub trait MergeKey: Copy {
+    type Key: Ord;
+
+    fn merge_key(&self) -> Self::Key;
+}
+
+impl MergeKey for i64 {
+    type Key = i64;
+
+    #[inline]
+    fn merge_key(&self) -> i64 { *self }
+}
+
+impl MergeKey for Tuple {
+    type Key = u64;
+
+    #[inline]
+    fn merge_key(&self) -> u64 { self.key }
+}
+
+pub struct Merge<'a, T: MergeKey = i64> {
+    pub dataset: Vec<Peekable<Iter<'a, T>>>,
     pub winner_index: usize,
     pub loser_tree: Vec<usize>
 }
 
-impl Merge <'_> {
+impl<'a, T: MergeKey> Merge<'a, T> {
     // Returns the (winner, loser) indexes
     fn choose (
-        dataset: &mut [Peekable<Iter<'_, i64>>],
+        dataset: &mut [Peekable<Iter<'a, T>>],
         li: usize,
         ri: usize,
     ) -> (usize, usize) {
@@ -19,12 +42,12 @@ impl Merge <'_> {
             (None, None) => (li, ri),
             (Some(_), None) => (li, ri),
             (None, Some(_)) => (ri, li),
-            (Some(lv), Some(rv)) => if lv <= rv { (li, ri) } else { (ri, li) } 
+            (Some(lv), Some(rv)) => if lv.merge_key() <= rv.merge_key() { (li, ri) } else { (ri, li) }
         }
     }
 
     // Returns the index of the winner of the subtree and updates the subtree sturcture in ds.
-    fn build_subtree (node: usize, lo: usize, hi: usize, losers: &mut [usize], ds: &mut [Peekable<Iter<'_, i64>>]) -> usize {
+    fn build_subtree (node: usize, lo: usize, hi: usize, losers: &mut [usize], ds: &mut [Peekable<Iter<'a, T>>]) -> usize {
         if hi - lo == 1 {
             return lo;
         }
@@ -42,17 +65,21 @@ impl Merge <'_> {
     }
 
 
-    fn initialize_loser_tree(ds: &mut [Peekable<Iter<'_, i64>>]) -> (Vec<usize>, usize) {
+    fn initialize_loser_tree(ds: &mut [Peekable<Iter<'a, T>>]) -> (Vec<usize>, usize) {
         let k = ds.len();
         let mut loser_tree = vec![usize::MAX; k - 1];
-        println!("init len {}", loser_tree.len());
 
         let winner_index = Self::build_subtree(0, 0, k, &mut loser_tree, ds);
 
         (loser_tree, winner_index)
     }
 
-    pub fn new(mut dataset: Vec<Peekable<Iter<'_, i64>>>) -> Merge {
+    // The inputs are padded with empty ones to a power of two, at least 2, so
+    // that the leaves of the tree line up with the heap layout bubble_up walks.
+    pub fn new(mut dataset: Vec<Peekable<Iter<'a, T>>>) -> Merge<'a, T> {
+        let k = dataset.len().max(2).next_power_of_two();
+        dataset.resize_with(k, || [].iter().peekable());
+
         let (loser_tree, winner_index) = Self::initialize_loser_tree(&mut dataset);
         Merge {dataset, winner_index, loser_tree}
     }
@@ -77,10 +104,10 @@ impl Merge <'_> {
     }
 }
 
-impl Iterator for Merge<'_> {
-    type Item = i64;
+impl<T: MergeKey> Iterator for Merge<'_, T> {
+    type Item = T;
 
-    fn next(&mut self) -> Option<i64> {
+    fn next(&mut self) -> Option<T> {
         match self.dataset[self.winner_index].next() {
             None => None,
             Some(value) => {
@@ -91,6 +118,36 @@ impl Iterator for Merge<'_> {
     }
 }
 
+// Sorts cache sized runs of `run_len` tuples in place and merges them with one
+// multiway loser tree merge into a new, fully sorted vector.
+pub fn merge_sort_runs<T: MergeKey>(data: &mut [T], run_len: usize) -> Vec<T> {
+    context::unbounded(|ctx| merge_sort_runs_ctx(data, run_len, ctx))
+}
+
+// Checks ctx before every run it sorts and every CHECK_INTERVAL values it
+// merges, and reports the sorted runs to ctx.
+pub fn merge_sort_runs_ctx<T: MergeKey>(data: &mut [T], run_len: usize, ctx: &JoinContext) -> Result<Vec<T>, JoinError> {
+    assert!(run_len > 0);
+
+    for run in data.chunks_mut(run_len) {
+        ctx.check()?;
+        run.sort_by_key(|t| t.merge_key());
+        ctx.add_sorted(run.len());
+    }
+    if data.len() <= run_len {
+        return Ok(data.to_vec());
+    }
+
+    let runs = data.chunks(run_len).map(|run| run.iter().peekable()).collect();
+    let mut merge = Merge::new(runs);
+    let mut output = Vec::with_capacity(data.len());
+    while output.len() < data.len() {
+        ctx.check()?;
+        output.extend(merge.by_ref().take(CHECK_INTERVAL));
+    }
+    Ok(output)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,4 +186,30 @@ mod tests {
         let res: Vec<i64> = m.collect();
         assert_eq!(vec![1, 2, 2, 3, 5, 6, 8, 9, 10, 11, 12, 14], res);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn uneven_number_of_inputs() {
+        for k in 0..7 {
+            let data: Vec<Vec<i64>> = (0..k).map(|i| (0..5).map(|j| j * 7 + i).collect()).collect();
+            let dataset: Vec<Peekable<Iter<i64>>> = data.iter().map(|v| v.iter().peekable()).collect();
+
+            let mut expected: Vec<i64> = data.iter().flatten().copied().collect();
+            expected.sort();
+            assert_eq!(Merge::new(dataset).collect::<Vec<i64>>(), expected, "{k} inputs");
+        }
+    }
+
+    #[test]
+    fn merge_sort_runs_test() {
+        let mut data: Vec<Tuple> = (0..1000u64).map(|i| Tuple::new((i * 7919) % 613, i)).collect();
+        let sorted = merge_sort_runs(&mut data, 64);
+
+        assert_eq!(sorted.len(), 1000);
+        assert!(sorted.is_sorted_by_key(|t| t.key));
+        let mut expected = data.clone();
+        expected.sort_by_key(|t| (t.key, t.payload));
+        let mut actual = sorted.clone();
+        actual.sort_by_key(|t| (t.key, t.payload));
+        assert_eq!(actual, expected);
+    }
+}
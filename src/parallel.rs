@@ -1,27 +1,74 @@
 use std::{ptr, thread};
 
-use crate::tuples::Tuple;
+use crate::{affinity, context::{self, JoinContext, JoinError, CHECK_INTERVAL}, histograms, merge, tuples::Tuple};
 
 pub fn sort_runs_parallel(table: &mut Vec<Tuple>, chunk_count: usize) {
+    context::unbounded(|ctx| sort_runs_parallel_ctx(table, chunk_count, ctx))
+}
+
+// Every run is sorted with sort_chunk_ctx.
+pub fn sort_runs_parallel_ctx(table: &mut [Tuple], chunk_count: usize, ctx: &JoinContext) -> Result<(), JoinError> {
     assert!(chunk_count > 0);
 
-    let chunk_size = table.len().div_ceil(chunk_count);
+    let chunk_size = table.len().div_ceil(chunk_count).max(1);
 
     thread::scope(|s| {
         let mut handles = Vec::new();
-        for chunk in table.chunks_mut(chunk_size) {
-            handles.push(s.spawn(move || chunk.sort_by_key(|t| t.key)));
+        for (chunk_index, chunk) in table.chunks_mut(chunk_size).enumerate() {
+            handles.push(affinity::spawn(s, chunk_index, move || sort_chunk_ctx(chunk, ctx)));
         }
-        for h in handles {
-            h.join().unwrap();
-        }
-    });
+        handles.into_iter().try_for_each(|h| h.join().unwrap())
+    })?;
+    ctx.check()
+}
+
+// Sorts a chunk by key in place. A chunk longer than CHECK_INTERVAL is sorted
+// in sub-runs of CHECK_INTERVAL tuples that are then merged, see
+// merge::merge_sort_runs_ctx, so a cancelled or expired ctx stops the sort
+// within one sub-run rather than after the whole chunk. The merge goes through
+// a buffer of the chunk's size.
+pub fn sort_chunk_ctx(chunk: &mut [Tuple], ctx: &JoinContext) -> Result<(), JoinError> {
+    if chunk.len() <= CHECK_INTERVAL {
+        ctx.check()?;
+        chunk.sort_by_key(|t| t.key);
+        ctx.add_sorted(chunk.len());
+        return Ok(());
+    }
+    let sorted = merge::merge_sort_runs_ctx(chunk, CHECK_INTERVAL, ctx)?;
+    chunk.copy_from_slice(&sorted);
+    Ok(())
 }
 
 pub fn chunk_histograms(table: &Vec<Tuple>, chunk_count: usize) -> Vec<Vec<u64>> {
     assert!(chunk_count >= 2);
 
-    let chunk_size = table.len().div_ceil(chunk_count);
+    let bits_prefix = chunk_count.ilog2();
+    let num_bins   = 2usize.pow(bits_prefix);
+
+    assert!(bits_prefix > 0);
+    assert!(chunk_count == num_bins);
+
+    radix_histograms(table, chunk_count, 64 - bits_prefix, bits_prefix)
+}
+
+// Only every stride-th tuple of a chunk feeds its heavy hitter summary.
+pub const HEAVY_HITTER_SAMPLE_STRIDE: usize = 8;
+
+// The sample of a chunk is taken block by block, so every block must start on
+// a sampled tuple.
+const _: () = assert!(CHECK_INTERVAL.is_multiple_of(HEAVY_HITTER_SAMPLE_STRIDE));
+
+// chunk_histograms that also detects heavy hitters in the same pass. Next to
+// its histogram, every chunk returns a Misra-Gries summary with `capacity`
+// counters over a sample of its keys (see histograms::heavy_hitters).
+pub fn chunk_histograms_heavy_hitters(table: &[Tuple], chunk_count: usize, capacity: usize) -> (Vec<Vec<u64>>, Vec<histograms::KeySummary>) {
+    context::unbounded(|ctx| chunk_histograms_heavy_hitters_ctx(table, chunk_count, capacity, ctx))
+}
+
+pub fn chunk_histograms_heavy_hitters_ctx(table: &[Tuple], chunk_count: usize, capacity: usize, ctx: &JoinContext) -> Result<(Vec<Vec<u64>>, Vec<histograms::KeySummary>), JoinError> {
+    assert!(chunk_count >= 2);
+
+    let chunk_size = table.len().div_ceil(chunk_count).max(1);
     let bits_prefix = chunk_count.ilog2();
     let num_bins   = 2usize.pow(bits_prefix);
 
@@ -31,43 +78,154 @@ pub fn chunk_histograms(table: &Vec<Tuple>, chunk_count: usize) -> Vec<Vec<u64>>
     thread::scope(|s| {
         let mut handles = Vec::new();
         for (chunk_index, chunk) in table.chunks(chunk_size).enumerate() {
-            handles.push(s.spawn(move || {
-                let mut histogram: Vec<u64> = vec![0; num_bins];
-
-                for t in chunk {
-                    let key = t.key;
-                    let histogram_index = (key >> (64 - bits_prefix)) as usize;
-                    histogram[histogram_index] += 1;
+            handles.push(affinity::spawn(s, chunk_index, move || {
+                let mut histogram = vec![0; num_bins];
+                let mut summary = histograms::MisraGries::new(capacity);
+                for block in chunk.chunks(CHECK_INTERVAL) {
+                    ctx.check()?;
+                    for t in block {
+                        histogram[radix_bin(t.key, 64 - bits_prefix, bits_prefix)] += 1;
+                    }
+                    for t in block.iter().step_by(HEAVY_HITTER_SAMPLE_STRIDE) {
+                        summary.insert(t.key);
+                    }
                 }
+                Ok((chunk_index, histogram, summary.into_summary()))
+            }));
+        }
+
+        let mut histograms: Vec<Vec<u64>> = vec![vec![0; num_bins]; chunk_count];
+        let mut summaries: Vec<histograms::KeySummary> = vec![Vec::new(); chunk_count];
+        for h in handles {
+            let (chunk_index, histogram, summary) = h.join().unwrap()?;
+            histograms[chunk_index] = histogram;
+            summaries[chunk_index] = summary;
+        }
+        Ok((histograms, summaries))
+    })
+}
+
+// Bin index of a key when partitioning on `bits` bits starting at bit `shift`.
+#[inline]
+pub fn radix_bin(key: u64, shift: u32, bits: u32) -> usize {
+    ((key >> shift) & ((1u64 << bits) - 1)) as usize
+}
+
+// Bin index of a key when range partitioning on sorted splitters. Bin i holds
+// the keys in [splitters[i - 1], splitters[i]).
+#[inline]
+pub fn range_bin(key: u64, splitters: &[u64]) -> usize {
+    splitters.partition_point(|s| *s <= key)
+}
+
+fn chunk_histogram<F: Fn(u64) -> usize>(chunk: &[Tuple], num_bins: usize, bin: &F, ctx: &JoinContext) -> Result<Vec<u64>, JoinError> {
+    let mut histogram: Vec<u64> = vec![0; num_bins];
+    for block in chunk.chunks(CHECK_INTERVAL) {
+        ctx.check()?;
+        for t in block {
+            histogram[bin(t.key)] += 1;
+        }
+    }
+    Ok(histogram)
+}
+
+// Splits the table into chunk_count chunks and computes, for each chunk, a
+// histogram over num_bins bins. `bin` maps a key to its bin. The number of bins
+// is independent of the chunk count.
+pub fn bin_histograms<F>(table: &[Tuple], chunk_count: usize, num_bins: usize, bin: F) -> Vec<Vec<u64>>
+where
+    F: Fn(u64) -> usize + Sync
+{
+    context::unbounded(|ctx| bin_histograms_ctx(table, chunk_count, num_bins, bin, ctx))
+}
 
-                (chunk_index, histogram)
+pub fn bin_histograms_ctx<F>(table: &[Tuple], chunk_count: usize, num_bins: usize, bin: F, ctx: &JoinContext) -> Result<Vec<Vec<u64>>, JoinError>
+where
+    F: Fn(u64) -> usize + Sync
+{
+    assert!(chunk_count > 0);
+    assert!(num_bins > 0);
+
+    let chunk_size = table.len().div_ceil(chunk_count).max(1);
+    let bin = &bin;
+
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (chunk_index, chunk) in table.chunks(chunk_size).enumerate() {
+            handles.push(affinity::spawn(s, chunk_index, move || {
+                Ok((chunk_index, chunk_histogram(chunk, num_bins, bin, ctx)?))
             }));
         }
 
-        let mut histograms: Vec<Vec<u64>> = vec![Vec::new(); chunk_count];
+        // Chunks past the end of a short table contribute nothing.
+        let mut histograms: Vec<Vec<u64>> = vec![vec![0; num_bins]; chunk_count];
         for h in handles {
-            let (chunk_index, histogram) = h.join().unwrap();
+            let (chunk_index, histogram) = h.join().unwrap()?;
             histograms[chunk_index] = histogram;
         }
-        histograms
+        Ok(histograms)
     })
 }
 
+// Histograms over 2^bits bins using the key bits [shift, shift + bits).
+pub fn radix_histograms(table: &[Tuple], chunk_count: usize, shift: u32, bits: u32) -> Vec<Vec<u64>> {
+    context::unbounded(|ctx| radix_histograms_ctx(table, chunk_count, shift, bits, ctx))
+}
+
+pub fn radix_histograms_ctx(table: &[Tuple], chunk_count: usize, shift: u32, bits: u32, ctx: &JoinContext) -> Result<Vec<Vec<u64>>, JoinError> {
+    assert!(bits > 0 && bits < 64 && shift + bits <= 64);
+    bin_histograms_ctx(table, chunk_count, 1 << bits, |key| radix_bin(key, shift, bits), ctx)
+}
+
+// Histograms over splitters.len() + 1 key ranges.
+pub fn range_histograms(table: &[Tuple], chunk_count: usize, splitters: &[u64]) -> Vec<Vec<u64>> {
+    context::unbounded(|ctx| range_histograms_ctx(table, chunk_count, splitters, ctx))
+}
+
+pub fn range_histograms_ctx(table: &[Tuple], chunk_count: usize, splitters: &[u64], ctx: &JoinContext) -> Result<Vec<Vec<u64>>, JoinError> {
+    debug_assert!(splitters.is_sorted());
+    bin_histograms_ctx(table, chunk_count, splitters.len() + 1, |key| range_bin(key, splitters), ctx)
+}
+
 struct Cursor(*mut Tuple);
 unsafe impl Send for Cursor {}
 
-pub fn scatter(table: &Vec<Tuple>, chunk_count: usize, prefix_sums: &Vec<Vec<u64>>) -> Vec<Vec<Tuple>> {
+pub fn scatter(table: &[Tuple], chunk_count: usize, prefix_sums: &[Vec<u64>]) -> Vec<Vec<Tuple>> {
     assert!(chunk_count >= 2);
 
-    let chunk_size = table.len().div_ceil(chunk_count);
     let bits_prefix = chunk_count.ilog2();
     let num_bins   = 2usize.pow(bits_prefix);
 
     assert!(bits_prefix > 0);
     assert!(chunk_count == num_bins);
 
+    radix_scatter(table, chunk_count, 64 - bits_prefix, bits_prefix, prefix_sums)
+}
+
+// Writes each tuple of the table to the output chunk selected by `bin`. The
+// prefix sums must come from bin_histograms called with the same chunk_count,
+// num_bins and bin function.
+pub fn bin_scatter<F>(table: &[Tuple], chunk_count: usize, num_bins: usize, bin: F, prefix_sums: &[Vec<u64>]) -> Vec<Vec<Tuple>>
+where
+    F: Fn(u64) -> usize + Sync
+{
+    context::unbounded(|ctx| bin_scatter_ctx(table, chunk_count, num_bins, bin, prefix_sums, ctx))
+}
+
+// A scatter that stops early leaves the output chunks partly written; they are
+// dropped with the error.
+pub fn bin_scatter_ctx<F>(table: &[Tuple], chunk_count: usize, num_bins: usize, bin: F, prefix_sums: &[Vec<u64>], ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError>
+where
+    F: Fn(u64) -> usize + Sync
+{
+    assert!(chunk_count > 0);
+    assert!(prefix_sums.len() == chunk_count + 1);
+
+    let chunk_size = table.len().div_ceil(chunk_count).max(1);
+    let bin = &bin;
+
     // The last prefix sum is equivalent to the final sizes of the chunks.
-    let final_chunk_sizes : Vec<usize> = (0..chunk_count)
+    let final_chunk_sizes : Vec<usize> = (0..num_bins)
         .map(|b| prefix_sums[chunk_count][b] as usize)
         .collect();
 
@@ -76,37 +234,89 @@ pub fn scatter(table: &Vec<Tuple>, chunk_count: usize, prefix_sums: &Vec<Vec<u64
         .collect();
 
     thread::scope(|s| {
+        let mut handles = Vec::new();
         for (chunk_index, chunk) in table.chunks(chunk_size).enumerate() {
             let mut starts: Vec<Cursor> = Vec::with_capacity(num_bins);
-            let mut ends: Vec<Cursor> = Vec::with_capacity(num_bins);
 
-            for out_chunk in 0..chunk_count {
+            for (out_chunk, final_chunk) in final_chunks.iter_mut().enumerate() {
                 let start = prefix_sums[chunk_index][out_chunk] as usize;
                 let end   = prefix_sums[chunk_index + 1][out_chunk] as usize;
+                debug_assert!(start <= end && end <= final_chunk.len());
 
-                let base_ptr: *mut Tuple = final_chunks[out_chunk].as_mut_ptr();
+                let base_ptr: *mut Tuple = final_chunk.as_mut_ptr();
 
                 unsafe {
                     starts.push(Cursor(base_ptr.add(start)));
-                    ends.push(Cursor(base_ptr.add(end)));
                 }
             }
 
-            s.spawn(move || {
+            handles.push(affinity::spawn(s, chunk_index, move || {
                 let mut curs = starts;
 
-                for t in chunk {
-                    let key = t.key;
-                    let bin_index = (key >> (64 - bits_prefix)) as usize;
+                for block in chunk.chunks(CHECK_INTERVAL) {
+                    ctx.check()?;
+                    for t in block {
+                        let bin_index = bin(t.key);
 
-                    unsafe {
-                        ptr::write(curs[bin_index].0, *t);
-                        curs[bin_index].0 = curs[bin_index].0.add(1);
+                        unsafe {
+                            ptr::write(curs[bin_index].0, *t);
+                            curs[bin_index].0 = curs[bin_index].0.add(1);
+                        }
                     }
+                    ctx.add_scattered(block.len());
                 }
-            });
+                Ok(())
+            }));
         }
-    });
+        handles.into_iter().try_for_each(|h| h.join().unwrap())
+    })?;
+
+    Ok(final_chunks)
+}
+
+// Scatter on the key bits [shift, shift + bits). The prefix sums must come
+// from radix_histograms with the same arguments.
+pub fn radix_scatter(table: &[Tuple], chunk_count: usize, shift: u32, bits: u32, prefix_sums: &[Vec<u64>]) -> Vec<Vec<Tuple>> {
+    context::unbounded(|ctx| radix_scatter_ctx(table, chunk_count, shift, bits, prefix_sums, ctx))
+}
+
+pub fn radix_scatter_ctx(table: &[Tuple], chunk_count: usize, shift: u32, bits: u32, prefix_sums: &[Vec<u64>], ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError> {
+    assert!(bits > 0 && bits < 64 && shift + bits <= 64);
+    bin_scatter_ctx(table, chunk_count, 1 << bits, |key| radix_bin(key, shift, bits), prefix_sums, ctx)
+}
+
+// Scatter on key ranges. The prefix sums must come from range_histograms with
+// the same splitters.
+pub fn range_scatter(table: &[Tuple], chunk_count: usize, splitters: &[u64], prefix_sums: &[Vec<u64>]) -> Vec<Vec<Tuple>> {
+    context::unbounded(|ctx| range_scatter_ctx(table, chunk_count, splitters, prefix_sums, ctx))
+}
+
+pub fn range_scatter_ctx(table: &[Tuple], chunk_count: usize, splitters: &[u64], prefix_sums: &[Vec<u64>], ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError> {
+    bin_scatter_ctx(table, chunk_count, splitters.len() + 1, |key| range_bin(key, splitters), prefix_sums, ctx)
+}
+
+// Single threaded radix partitioning of one chunk. Used by the later passes of a
+// multi-pass partitioning, where the parallelism comes from working on many
+// partitions at once.
+pub fn radix_partition(chunk: &[Tuple], shift: u32, bits: u32) -> Vec<Vec<Tuple>> {
+    context::unbounded(|ctx| radix_partition_ctx(chunk, shift, bits, ctx))
+}
 
-    final_chunks
-}
\ No newline at end of file
+pub fn radix_partition_ctx(chunk: &[Tuple], shift: u32, bits: u32, ctx: &JoinContext) -> Result<Vec<Vec<Tuple>>, JoinError> {
+    assert!(bits > 0 && bits < 64 && shift + bits <= 64);
+
+    let bin = |key| radix_bin(key, shift, bits);
+    let histogram = chunk_histogram(chunk, 1 << bits, &bin, ctx)?;
+    let mut partitions: Vec<Vec<Tuple>> = histogram.iter()
+        .map(|n| Vec::with_capacity(*n as usize))
+        .collect();
+
+    for block in chunk.chunks(CHECK_INTERVAL) {
+        ctx.check()?;
+        for t in block {
+            partitions[bin(t.key)].push(*t);
+        }
+        ctx.add_scattered(block.len());
+    }
+    Ok(partitions)
+}
@@ -0,0 +1,338 @@
+use std::{collections::HashSet, fmt::{self, Write}};
+
+use crate::{algorithms::{partition_count, JoinConfig, JoinResult, Registry}, hash_join::{self, RadixConfig}, histograms, parallel, tuples::Tuple};
+
+// Cost-based choice between the algorithms of a Registry. The planner looks at
+// a sample of both inputs, predicts the elapsed time of every algorithm it has
+// a cost model for in units of tuple touches per worker, and runs the
+// cheapest. Algorithms without a cost model are never picked.
+
+// Tuples sampled from every input, evenly spaced.
+pub const PLANNER_SAMPLE_SIZE: usize = 4096;
+
+// Top key bits the sample histogram partitions on to estimate how uneven a
+// radix partitioning of the input would be.
+const SKEW_HISTOGRAM_BITS: u32 = 6;
+
+// Cost of a random access into a hash table that does not fit in cache,
+// relative to a sequential tuple touch.
+pub const CACHE_MISS_FACTOR: f64 = 4.0;
+
+// Estimates for one join input, taken from a sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TableStats {
+    pub len: usize,
+    pub min_key: u64,
+    pub max_key: u64,
+    // Fraction of consecutive sample tuples in key order. 1 for sorted input,
+    // about 0.5 for random order.
+    pub sortedness: f64,
+    // Fraction of sample tuples that repeat a key seen before in the sample
+    pub duplicate_ratio: f64,
+    pub distinct_estimate: f64,
+    // Share of the input held by the most frequent key
+    pub top_key_share: f64,
+    // Largest bin of a radix partitioning on the top key bits, relative to a
+    // bin of average size
+    pub radix_skew: f64
+}
+
+impl TableStats {
+    pub fn collect(table: &[Tuple]) -> TableStats {
+        let stride = table.len().div_ceil(PLANNER_SAMPLE_SIZE).max(1);
+        let sample: Vec<Tuple> = table.iter().step_by(stride).copied().collect();
+        if sample.is_empty() {
+            return TableStats {
+                len: 0, min_key: 0, max_key: 0, sortedness: 1.0, duplicate_ratio: 0.0,
+                distinct_estimate: 0.0, top_key_share: 0.0, radix_skew: 1.0
+            };
+        }
+        let n = sample.len() as f64;
+
+        let min_key = sample.iter().map(|t| t.key).min().unwrap();
+        let max_key = sample.iter().map(|t| t.key).max().unwrap();
+
+        let in_order = sample.windows(2).filter(|w| w[0].key <= w[1].key).count();
+        let sortedness = if sample.len() < 2 { 1.0 } else { in_order as f64 / (n - 1.0) };
+
+        let distinct = sample.iter().map(|t| t.key).collect::<HashSet<u64>>().len() as f64;
+        let duplicate_ratio = 1.0 - distinct / n;
+        let distinct_estimate = (table.len() as f64 * distinct / n).max(distinct);
+
+        let summary = histograms::misra_gries(sample.iter(), 16);
+        let top_key_share = summary.iter().map(|(_, c)| *c).max().unwrap_or(0) as f64 / n;
+
+        let histogram = &parallel::radix_histograms(&sample, 1, 64 - SKEW_HISTOGRAM_BITS, SKEW_HISTOGRAM_BITS)[0];
+        let largest = *histogram.iter().max().unwrap() as f64;
+        let radix_skew = largest / (n / histogram.len() as f64).max(1.0);
+
+        TableStats {
+            len: table.len(), min_key, max_key, sortedness, duplicate_ratio,
+            distinct_estimate, top_key_share, radix_skew: radix_skew.max(1.0)
+        }
+    }
+}
+
+// Sort cost of n tuples. The standard library sort finds presorted runs, so
+// sorted input costs a single pass.
+fn sort_cost(n: f64, sortedness: f64) -> f64 {
+    let unsorted = ((1.0 - sortedness) * 2.0).clamp(0.0, 1.0);
+    n * (1.0 + unsorted * n.max(2.0).log2())
+}
+
+// Expected number of output tuples: every left key meets right tuples at the
+// rate of a uniform key distribution over the larger distinct count.
+pub fn estimate_output(left: &TableStats, right: &TableStats) -> f64 {
+    if left.len == 0 || right.len == 0 || left.max_key < right.min_key || right.max_key < left.min_key {
+        return 0.0;
+    }
+    let distinct = left.distinct_estimate.max(right.distinct_estimate).max(1.0);
+    left.len as f64 * right.len as f64 / distinct
+}
+
+// Predicted cost of a registered algorithm, or None if there is no model for it.
+pub fn estimate_cost(algorithm: &str, left: &TableStats, right: &TableStats, thread_count: usize, output: f64) -> Option<f64> {
+    let (l, r) = (left.len as f64, right.len as f64);
+    let t = thread_count as f64;
+    let p = partition_count(thread_count) as f64;
+
+    // The most frequent key cannot be split by a partitioning on keys
+    let hot = |partitions: f64| (left.top_key_share * partitions).max(1.0);
+
+    let cost = match algorithm {
+        "nested_loop" => l * r + output,
+        "sort_merge" => sort_cost(l, left.sortedness) + sort_cost(r, right.sortedness) + l + r + output,
+        // Every worker merges its private chunk with every public run
+        "basic_mpsm" => sort_cost(r / t, right.sortedness) + sort_cost(l / t, left.sortedness) + l + r + output / t,
+        "partitioned_mpsm" => {
+            let skew = left.radix_skew.min(p).max(hot(p));
+            sort_cost(r / t, right.sortedness) + 2.0 * l / p
+                + skew * (sort_cost(l / p, left.sortedness) + l) + r / p + skew * output / p
+        }
+        "range_partitioned_mpsm" => {
+            let skew = hot(t);
+            sort_cost(r / t, right.sortedness) + 2.0 * l / t * t.log2().max(1.0)
+                + skew * (sort_cost(l / t, left.sortedness) + l) + r / t + skew * output / t
+        }
+        // Both inputs are radix partitioned, then every worker sorts and joins
+        // one pair of partitions with a single merge
+        "mway_sort_merge" => {
+            let skew = left.radix_skew.max(right.radix_skew).min(p).max(hot(p));
+            2.0 * (l + r) / p
+                + skew * (sort_cost(l / p, left.sortedness) + sort_cost(r / p, right.sortedness) + (l + r) / p + output / p)
+        }
+        "radix_hash" => {
+            let radix = RadixConfig::for_build_size(left.len.min(right.len), hash_join::CACHE_PARTITION_TUPLES);
+            let skew = hot(t);
+            2.0 * radix.passes as f64 * (l + r) / t + skew * ((l + r) / t + output / t)
+        }
+        "no_partitioning" => {
+            let miss = if left.len.min(right.len) > hash_join::CACHE_PARTITION_TUPLES { CACHE_MISS_FACTOR } else { 1.0 };
+            miss * (l + r) / t + output / t
+        }
+        _ => return None
+    };
+    Some(cost)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostEstimate {
+    pub algorithm: &'static str,
+    // None when the planner has no cost model for the algorithm
+    pub cost: Option<f64>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub left: TableStats,
+    pub right: TableStats,
+    pub thread_count: usize,
+    pub output_estimate: f64,
+    // One estimate per registered algorithm, in registry order
+    pub estimates: Vec<CostEstimate>,
+    pub choice: &'static str
+}
+
+impl Plan {
+    pub fn explain(&self) -> String {
+        let mut out = String::new();
+        let table = |out: &mut String, name: &str, s: &TableStats| {
+            writeln!(out, "{name}: {} tuples, keys {}..={}, sortedness {:.2}, duplicates {:.2}, ~{:.0} distinct, top key {:.3}, radix skew {:.2}",
+                s.len, s.min_key, s.max_key, s.sortedness, s.duplicate_ratio, s.distinct_estimate, s.top_key_share, s.radix_skew).unwrap();
+        };
+        table(&mut out, "left", &self.left);
+        table(&mut out, "right", &self.right);
+        writeln!(out, "threads: {}, estimated output: {:.0} tuples", self.thread_count, self.output_estimate).unwrap();
+        for e in &self.estimates {
+            let marker = if e.algorithm == self.choice { "*" } else { " " };
+            match e.cost {
+                Some(cost) => writeln!(out, "{marker} {:<24} {:>16.0}", e.algorithm, cost).unwrap(),
+                None => writeln!(out, "{marker} {:<24} {:>16}", e.algorithm, "no cost model").unwrap()
+            }
+        }
+        write!(out, "choice: {}", self.choice).unwrap();
+        out
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.explain())
+    }
+}
+
+pub struct Planner {
+    registry: Registry
+}
+
+impl Planner {
+    pub fn new(registry: Registry) -> Planner {
+        Planner {registry}
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    // Picks the cheapest algorithm with a cost model, or the first registered one
+    // when none has a model. Returns None only for an empty registry.
+    pub fn plan(&self, left: &[Tuple], right: &[Tuple], config: &JoinConfig) -> Option<Plan> {
+        let left = TableStats::collect(left);
+        let right = TableStats::collect(right);
+        let output_estimate = estimate_output(&left, &right);
+
+        let estimates: Vec<CostEstimate> = self.registry.algorithms()
+            .map(|a| CostEstimate {
+                algorithm: a.name(),
+                cost: estimate_cost(a.name(), &left, &right, config.thread_count, output_estimate)
+            })
+            .collect();
+
+        let choice = estimates.iter()
+            .filter_map(|e| e.cost.map(|c| (e.algorithm, c)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(name, _)| name)
+            .or_else(|| estimates.first().map(|e| e.algorithm))?;
+
+        Some(Plan {left, right, thread_count: config.thread_count, output_estimate, estimates, choice})
+    }
+
+    // Plans the join and runs the chosen algorithm.
+    pub fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> Option<(Plan, JoinResult)> {
+        let plan = self.plan(&left, &right, config)?;
+        let result = self.registry.get(plan.choice)?.join(left, right, config);
+        Some((plan, result))
+    }
+}
+
+impl Default for Planner {
+    fn default() -> Planner {
+        Planner::new(Registry::with_defaults())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{algorithms::{JoinAlgorithm, OutputMode, SortMergeJoin}, infrastructure, join};
+
+    use super::*;
+
+    #[test]
+    fn table_stats_test() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (fact, dimension) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+
+        let stats = TableStats::collect(&dimension);
+        assert_eq!(stats.len, 10000);
+        assert!(stats.sortedness > 0.4 && stats.sortedness < 0.6);
+        assert_eq!(stats.duplicate_ratio, 0.0);
+        assert!(stats.radix_skew < 2.0);
+
+        assert!(fact.len() > 20000);
+        // A small fact table fits the sample. About 2.3 tuples per key.
+        let (small_fact, _) = infrastructure::gen_tables(1000, 0.7, &mut rng);
+        assert!(small_fact.len() < PLANNER_SAMPLE_SIZE);
+        let stats = TableStats::collect(&small_fact);
+        assert!(stats.duplicate_ratio > 0.5, "{}", stats.duplicate_ratio);
+
+        let mut sorted = dimension.clone();
+        sorted.sort_by_key(|t| t.key);
+        assert_eq!(TableStats::collect(&sorted).sortedness, 1.0);
+
+        // Dense keys share their top bits
+        let dense: Vec<Tuple> = (0..5000).map(|k| Tuple::new(k, k)).collect();
+        assert!(TableStats::collect(&dense).radix_skew > 32.0);
+    }
+
+    #[test]
+    fn planner_picks_cheapest_estimate() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(20000, 0.7, &mut rng);
+
+        let planner = Planner::default();
+        let plan = planner.plan(&lt, &rt, &JoinConfig::new(4, OutputMode::Count)).unwrap();
+
+        assert_eq!(plan.estimates.len(), planner.registry().names().len());
+        let cheapest = plan.estimates.iter().map(|e| e.cost.unwrap()).fold(f64::INFINITY, f64::min);
+        let chosen = plan.estimates.iter().find(|e| e.algorithm == plan.choice).unwrap();
+        assert_eq!(chosen.cost, Some(cheapest));
+        assert_ne!(plan.choice, "nested_loop");
+
+        let explain = plan.explain();
+        for name in planner.registry().names() {
+            assert!(explain.contains(name), "{explain}");
+        }
+        assert!(explain.ends_with(&format!("choice: {}", plan.choice)));
+    }
+
+    #[test]
+    fn planner_avoids_radix_partitioning_dense_keys() {
+        let lt: Vec<Tuple> = (0..200000).map(|k| Tuple::new(k % 50000, k)).collect();
+        let rt: Vec<Tuple> = (0..50000).map(|k| Tuple::new(k, k)).collect();
+
+        let plan = Planner::default().plan(&lt, &rt, &JoinConfig::new(8, OutputMode::Count)).unwrap();
+        let cost = |name| plan.estimates.iter().find(|e| e.algorithm == name).unwrap().cost.unwrap();
+        assert!(cost("partitioned_mpsm") > cost("range_partitioned_mpsm"));
+    }
+
+    #[test]
+    fn compare_planner_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(5000, 0.7, &mut rng);
+        let expected = join::nested_loop_join(&lt, &rt);
+
+        let (plan, result) = Planner::default().join(lt, rt, &JoinConfig::new(4, OutputMode::Materialize)).unwrap();
+        let rows = result.into_rows().unwrap();
+        assert!(infrastructure::table_eq(&expected, &rows), "{plan}");
+    }
+
+    struct Unmodelled;
+
+    impl JoinAlgorithm for Unmodelled {
+        fn name(&self) -> &'static str { "unmodelled" }
+
+        fn join(&self, left: Vec<Tuple>, right: Vec<Tuple>, config: &JoinConfig) -> JoinResult {
+            SortMergeJoin.join(left, right, config)
+        }
+    }
+
+    #[test]
+    fn planner_falls_back_without_cost_model() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(2000, 0.7, &mut rng);
+        let expected = join::nested_loop_join(&lt, &rt);
+        let config = JoinConfig::new(4, OutputMode::Materialize);
+
+        assert!(Planner::new(Registry::new()).plan(&lt, &rt, &config).is_none());
+        assert!(Planner::new(Registry::new()).join(lt.clone(), rt.clone(), &config).is_none());
+
+        let mut registry = Registry::new();
+        registry.register(Box::new(Unmodelled));
+        let (plan, result) = Planner::new(registry).join(lt, rt, &config).unwrap();
+        assert_eq!(plan.choice, "unmodelled");
+        assert_eq!(plan.estimates[0].cost, None);
+        assert!(infrastructure::table_eq(&expected, &result.into_rows().unwrap()), "{plan}");
+    }
+}
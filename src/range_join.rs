@@ -0,0 +1,182 @@
+use std::thread;
+
+use crate::{parallel, search, tuples::Tuple};
+
+// Non-equality join predicates that select a contiguous key range of the right
+// input for every left tuple.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangePredicate {
+    // left.key BETWEEN right.key - d AND right.key + d
+    Band(u64),
+    // left.key < right.key
+    Less,
+    // left.key <= right.key
+    LessEqual,
+    // left.key > right.key
+    Greater,
+    // left.key >= right.key
+    GreaterEqual
+}
+
+impl RangePredicate {
+    pub fn matches(&self, left_key: u64, right_key: u64) -> bool {
+        match self {
+            RangePredicate::Band(d) => left_key.abs_diff(right_key) <= *d,
+            RangePredicate::Less => left_key < right_key,
+            RangePredicate::LessEqual => left_key <= right_key,
+            RangePredicate::Greater => left_key > right_key,
+            RangePredicate::GreaterEqual => left_key >= right_key
+        }
+    }
+
+    // Inclusive range of right keys that match a left key, or None if no key
+    // can match. Both ends never decrease as the left key grows, which lets a
+    // merge over sorted inputs slide the window forward.
+    pub fn right_bounds(&self, left_key: u64) -> Option<(u64, u64)> {
+        match self {
+            RangePredicate::Band(d) => Some((left_key.saturating_sub(*d), left_key.saturating_add(*d))),
+            RangePredicate::Less => left_key.checked_add(1).map(|lo| (lo, u64::MAX)),
+            RangePredicate::LessEqual => Some((left_key, u64::MAX)),
+            RangePredicate::Greater => left_key.checked_sub(1).map(|hi| (0, hi)),
+            RangePredicate::GreaterEqual => Some((0, left_key))
+        }
+    }
+}
+
+pub fn nested_loop_range_join(left: &[Tuple], right: &[Tuple], predicate: RangePredicate) -> Vec<(Tuple, Tuple)> {
+    let mut output = Vec::new();
+
+    for lt in left {
+        for rt in right {
+            if predicate.matches(lt.key, rt.key) {
+                output.push((*lt, *rt));
+            }
+        }
+    }
+
+    output
+}
+
+// Joins two sorted inputs on a range predicate. The start of the matching
+// window of each left tuple is found with a lower bound search that resumes
+// from the previous window start, and the window is then scanned up to its end.
+pub fn range_join_sorted(left: &[Tuple], right: &[Tuple], predicate: RangePredicate, output: &mut Vec<(Tuple, Tuple)>) {
+    let mut start = 0;
+
+    for lt in left {
+        let Some((lo, hi)) = predicate.right_bounds(lt.key) else { continue };
+
+        match search::lb_binary_search_by_key(&lo, &right[start..], |t| &t.key) {
+            Some(i) => start += i,
+            None => return // every later window starts past the end of right
+        }
+
+        for rt in right[start..].iter().take_while(|rt| rt.key <= hi) {
+            output.push((*lt, *rt));
+        }
+    }
+}
+
+pub fn sort_range_join(mut left: Vec<Tuple>, mut right: Vec<Tuple>, predicate: RangePredicate) -> Vec<(Tuple, Tuple)> {
+    left.sort_by_key(|t| t.key);
+    right.sort_by_key(|t| t.key);
+
+    let mut output = Vec::new();
+    range_join_sorted(&left, &right, predicate, &mut output);
+    output
+}
+
+// MPSM style range join. The right (public) input is sorted into runs in
+// parallel, then every worker sorts a chunk of the left (private) input and
+// runs range_join_sorted against each public run.
+pub fn mpsm_range_join(mut left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize, predicate: RangePredicate) -> Vec<Vec<(Tuple, Tuple)>> {
+    assert!(thread_count > 0);
+
+    parallel::sort_runs_parallel(&mut right, thread_count);
+
+    let public: &[Tuple] = &right;
+    let private_chunk_size = left.len().div_ceil(thread_count).max(1);
+    let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for private_chunk in left.chunks_mut(private_chunk_size) {
+            handles.push(s.spawn(move || {
+                private_chunk.sort_by_key(|t| t.key);
+
+                let mut output = Vec::new();
+                for public_chunk in public.chunks(public_chunk_size) {
+                    range_join_sorted(private_chunk, public_chunk, predicate, &mut output);
+                }
+                output
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    const PREDICATES: [RangePredicate; 6] = [
+        RangePredicate::Band(0),
+        RangePredicate::Band(3),
+        RangePredicate::Less,
+        RangePredicate::LessEqual,
+        RangePredicate::Greater,
+        RangePredicate::GreaterEqual
+    ];
+
+    fn small_key_table<R: Rng>(n: usize, max_key: u64, rng: &mut R) -> Vec<Tuple> {
+        (0..n).map(|_| Tuple::new(rng.random_range(0..max_key), rng.random())).collect()
+    }
+
+    #[test]
+    fn right_bounds_edges() {
+        assert_eq!(RangePredicate::Band(5).right_bounds(2), Some((0, 7)));
+        assert_eq!(RangePredicate::Band(5).right_bounds(u64::MAX - 1), Some((u64::MAX - 6, u64::MAX)));
+        assert_eq!(RangePredicate::Less.right_bounds(u64::MAX), None);
+        assert_eq!(RangePredicate::Greater.right_bounds(0), None);
+        assert_eq!(RangePredicate::GreaterEqual.right_bounds(0), Some((0, 0)));
+    }
+
+    #[test]
+    fn band_join_test() {
+        let left = vec![Tuple::new(10, 1), Tuple::new(20, 2)];
+        let right = vec![Tuple::new(7, 3), Tuple::new(12, 4), Tuple::new(18, 5), Tuple::new(30, 6)];
+
+        let output = sort_range_join(left, right, RangePredicate::Band(2));
+        assert_eq!(output, vec![
+            (Tuple::new(10, 1), Tuple::new(12, 4)),
+            (Tuple::new(20, 2), Tuple::new(18, 5))
+        ]);
+    }
+
+    #[test]
+    fn compare_range_joins_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let lt = small_key_table(300, 500, &mut rng);
+        let rt = small_key_table(400, 500, &mut rng);
+
+        for predicate in PREDICATES {
+            let nl_output = nested_loop_range_join(&lt, &rt, predicate);
+
+            let sm_output = sort_range_join(lt.clone(), rt.clone(), predicate);
+            assert!(infrastructure::table_eq(&nl_output, &sm_output), "{predicate:?}");
+
+            let mpsm_output = mpsm_range_join(lt.clone(), rt.clone(), 3, predicate)
+                .into_iter().flatten().collect::<Vec<(Tuple, Tuple)>>();
+            assert!(infrastructure::table_eq(&nl_output, &mpsm_output), "{predicate:?}");
+        }
+    }
+}
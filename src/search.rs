@@ -46,6 +46,37 @@ where
     if lo >= input.len() { None } else { Some(lo) }
 }
 
+// Lower bound search that probes positions 0, 1, 3, 7, ... until it passes
+// the target and then binary searches the last gap. Costs O(log i) for a
+// result at position i, so it suits cursors that advance by small but
+// unpredictable steps.
+pub fn lb_exponential_search<T: Ord>(target: T, input: &[T]) -> Option<usize> {
+    lb_exponential_search_by_key(&target, input, |v| v)
+}
+
+pub fn lb_exponential_search_by_key<T, K, F>(target: &K, input: &[T], key: F) -> Option<usize>
+where
+    F: Fn(&T) -> &K,
+    K: Ord
+{
+    let mut lo = 0;
+    let mut step = 1;
+
+    while lo < input.len() && key(&input[lo]) < target {
+        let next = lo + step;
+        if next >= input.len() || key(&input[next]) >= target {
+            let hi = next.min(input.len());
+            return lb_binary_search_by_key(target, &input[lo + 1..hi], &key)
+                .map(|i| lo + 1 + i)
+                .or(if hi < input.len() { Some(hi) } else { None });
+        }
+        lo = next;
+        step *= 2;
+    }
+
+    if lo < input.len() { Some(lo) } else { None }
+}
+
 pub fn lb_interpolation_search(target: u64, input: &[u64]) -> Option<usize> {
     if input.len() == 0 {
         return None;
@@ -172,6 +203,33 @@ mod test {
         assert_eq!(lb_interpolation_search(6, &vec![4, 5, 6, 6, 7, 8, 9]), Some(2));
     }
 
+    #[test]
+    fn lb_exponential_search_test() {
+        assert_eq!(lb_exponential_search(8, &Vec::new()), None);
+        assert_eq!(lb_exponential_search(8, &vec![5]), None);
+        assert_eq!(lb_exponential_search(5, &vec![5]), Some(0));
+        assert_eq!(lb_exponential_search(6, &vec![4, 5, 6, 6, 7, 8, 9]), Some(2));
+        assert_eq!(lb_exponential_search(6, &vec![4, 8]), Some(1));
+
+        let input: Vec<u64> = (0..100).map(|i| i / 3).collect();
+        for target in 0..40 {
+            assert_eq!(lb_exponential_search(target, &input), lb_linear_search(target, &input), "{target}");
+        }
+    }
+
+    #[test]
+    fn lb_search_test3() {
+        let mut rng = rand::rng();
+        let mut input = infrastructure::gen_keys(10000, &mut rng);
+        input.sort();
+        let target = rng.next_u64();
+
+        let res1 = lb_linear_search(target, &input);
+        let res2 = lb_exponential_search(target, &input);
+
+        assert_eq!(res1, res2);
+    }
+
     #[test]
     fn lb_search_test2() {
         let mut rng = rand::rng();
@@ -0,0 +1,274 @@
+use crate::tuples::{Joined, OuterJoined, Tuple};
+
+// Receives the output rows of a join one at a time. Joins are generic over
+// the sink, so a count or an aggregate compiles down to the inner merge loop
+// without any output buffer. `Vec<T>` is the sink that keeps every row.
+pub trait JoinSink<T> {
+    fn push(&mut self, row: T);
+}
+
+// Sinks that parallel joins create once per worker and that can be folded
+// into one result afterwards.
+pub trait CombineSink {
+    fn combine(&mut self, other: Self);
+}
+
+pub fn combine_all<S: CombineSink + Default>(sinks: impl IntoIterator<Item = S>) -> S {
+    let mut combined = S::default();
+    for sink in sinks {
+        combined.combine(sink);
+    }
+    combined
+}
+
+impl<T> JoinSink<T> for Vec<T> {
+    #[inline]
+    fn push(&mut self, row: T) {
+        Vec::push(self, row);
+    }
+}
+
+impl<T> CombineSink for Vec<T> {
+    fn combine(&mut self, other: Self) {
+        self.extend(other);
+    }
+}
+
+impl<T, S: JoinSink<T> + ?Sized> JoinSink<T> for &mut S {
+    #[inline]
+    fn push(&mut self, row: T) {
+        (**self).push(row);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CountSink {
+    pub count: u64
+}
+
+impl<T> JoinSink<T> for CountSink {
+    #[inline]
+    fn push(&mut self, _row: T) {
+        self.count += 1;
+    }
+}
+
+impl CombineSink for CountSink {
+    fn combine(&mut self, other: Self) {
+        self.count += other.count;
+    }
+}
+
+// Finalizer of splitmix64. Every output bit depends on every input bit.
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+// Hash of a single output row, used by ChecksumSink.
+pub trait RowHash {
+    fn row_hash(&self) -> u64;
+}
+
+impl RowHash for Tuple {
+    #[inline]
+    fn row_hash(&self) -> u64 {
+        mix(mix(self.key) ^ self.payload)
+    }
+}
+
+impl RowHash for Joined {
+    #[inline]
+    fn row_hash(&self) -> u64 {
+        mix(mix(mix(self.key) ^ self.left_payload) ^ self.right_payload)
+    }
+}
+
+impl RowHash for OuterJoined {
+    #[inline]
+    fn row_hash(&self) -> u64 {
+        // Which payloads are present is hashed on its own, so that a missing
+        // payload never hashes like any present one
+        let present = self.left_payload.is_some() as u64 | (self.right_payload.is_some() as u64) << 1;
+        let (left, right) = (self.left_payload.unwrap_or(0), self.right_payload.unwrap_or(0));
+        mix(mix(mix(mix(present) ^ self.key) ^ left) ^ right)
+    }
+}
+
+// Order independent checksum of the output: the wrapping sum of the row hashes.
+// Two joins produce the same checksum for the same multiset of rows no matter
+// how the rows are spread over the workers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumSink {
+    pub count: u64,
+    pub checksum: u64
+}
+
+impl<T: RowHash> JoinSink<T> for ChecksumSink {
+    #[inline]
+    fn push(&mut self, row: T) {
+        self.count += 1;
+        self.checksum = self.checksum.wrapping_add(row.row_hash());
+    }
+}
+
+impl CombineSink for ChecksumSink {
+    fn combine(&mut self, other: Self) {
+        self.count += other.count;
+        self.checksum = self.checksum.wrapping_add(other.checksum);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadStats {
+    pub count: u64,
+    pub sum: u128,
+    pub min: u64,
+    pub max: u64
+}
+
+impl PayloadStats {
+    #[inline]
+    pub fn add(&mut self, payload: u64) {
+        self.count += 1;
+        self.sum += payload as u128;
+        self.min = self.min.min(payload);
+        self.max = self.max.max(payload);
+    }
+
+    pub fn merge(&mut self, other: &PayloadStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+impl Default for PayloadStats {
+    fn default() -> PayloadStats {
+        PayloadStats {count: 0, sum: 0, min: u64::MAX, max: 0}
+    }
+}
+
+// Sum, min and max of the left and right payloads of the output. Missing
+// payloads of outer joins are skipped, and the tuples of semi and anti joins
+// count as left payloads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AggregateSink {
+    pub left: PayloadStats,
+    pub right: PayloadStats
+}
+
+impl JoinSink<Joined> for AggregateSink {
+    #[inline]
+    fn push(&mut self, row: Joined) {
+        self.left.add(row.left_payload);
+        self.right.add(row.right_payload);
+    }
+}
+
+impl JoinSink<OuterJoined> for AggregateSink {
+    #[inline]
+    fn push(&mut self, row: OuterJoined) {
+        if let Some(p) = row.left_payload { self.left.add(p); }
+        if let Some(p) = row.right_payload { self.right.add(p); }
+    }
+}
+
+impl JoinSink<Tuple> for AggregateSink {
+    #[inline]
+    fn push(&mut self, row: Tuple) {
+        self.left.add(row.payload);
+    }
+}
+
+impl CombineSink for AggregateSink {
+    fn combine(&mut self, other: Self) {
+        self.left.merge(&other.left);
+        self.right.merge(&other.right);
+    }
+}
+
+// Hands every row to a closure.
+pub struct FnSink<F>(pub F);
+
+impl<T, F: FnMut(T)> JoinSink<T> for FnSink<F> {
+    #[inline]
+    fn push(&mut self, row: T) {
+        (self.0)(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<Joined> {
+        vec![Joined::new(1, 10, 20), Joined::new(2, 5, 7), Joined::new(2, 6, 7)]
+    }
+
+    fn push_all<S: JoinSink<Joined>>(sink: &mut S, rows: &[Joined]) {
+        for row in rows {
+            sink.push(*row);
+        }
+    }
+
+    #[test]
+    fn count_sink_test() {
+        let mut sink = CountSink::default();
+        push_all(&mut sink, &rows());
+        assert_eq!(sink.count, 3);
+    }
+
+    #[test]
+    fn checksum_sink_is_order_independent() {
+        let mut forward = ChecksumSink::default();
+        push_all(&mut forward, &rows());
+
+        let mut split = vec![ChecksumSink::default(), ChecksumSink::default()];
+        let mut reversed = rows();
+        reversed.reverse();
+        push_all(&mut split[0], &reversed[..1]);
+        push_all(&mut split[1], &reversed[1..]);
+
+        assert_eq!(forward, combine_all(split));
+
+        let mut other = ChecksumSink::default();
+        push_all(&mut other, &[Joined::new(1, 10, 20), Joined::new(2, 5, 7), Joined::new(2, 6, 8)]);
+        assert_ne!(forward.checksum, other.checksum);
+    }
+
+    #[test]
+    fn outer_rows_hash_missing_payloads() {
+        let missing = OuterJoined::new(1, Some(0), None);
+        let zero = OuterJoined::new(1, Some(0), Some(0));
+        assert_ne!(missing.row_hash(), zero.row_hash());
+
+        // mix(0) == 0, so these must not be told apart by the payload alone
+        let missing = OuterJoined::new(1, None, Some(7));
+        let one = OuterJoined::new(1, Some(1), Some(7));
+        assert_ne!(missing.row_hash(), one.row_hash());
+        let both_missing = OuterJoined::new(1, None, None);
+        let both_one = OuterJoined::new(1, Some(1), Some(1));
+        assert_ne!(both_missing.row_hash(), both_one.row_hash());
+    }
+
+    #[test]
+    fn aggregate_sink_test() {
+        let mut sink = AggregateSink::default();
+        push_all(&mut sink, &rows());
+        assert_eq!(sink.left, PayloadStats {count: 3, sum: 21, min: 5, max: 10});
+        assert_eq!(sink.right, PayloadStats {count: 3, sum: 34, min: 7, max: 20});
+    }
+
+    #[test]
+    fn fn_sink_test() {
+        let mut keys = Vec::new();
+        push_all(&mut FnSink(|j: Joined| keys.push(j.key)), &rows());
+        assert_eq!(keys, vec![1, 2, 2]);
+    }
+}
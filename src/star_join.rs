@@ -0,0 +1,242 @@
+use std::thread;
+
+use crate::{affinity, join, sink::{FnSink, JoinSink}, tuples::{Joined, Tuple}};
+
+// Star join: one fact table joined with several dimension tables, each on its
+// own foreign key column of the fact table. A fact row is emitted once for
+// every combination of matching dimension tuples, so with unique dimension
+// keys every fact row that finds all of its dimensions yields one wide row.
+
+// Fact table stored by column. foreign_keys[d][i] is the key of row i into
+// dimension d.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FactTable {
+    pub foreign_keys: Vec<Vec<u64>>,
+    pub payloads: Vec<u64>
+}
+
+impl FactTable {
+    pub fn new(foreign_keys: Vec<Vec<u64>>, payloads: Vec<u64>) -> FactTable {
+        assert!(foreign_keys.iter().all(|column| column.len() == payloads.len()),
+            "every foreign key column must have one key per fact row");
+        FactTable {foreign_keys, payloads}
+    }
+
+    pub fn len(&self) -> usize {
+        self.payloads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payloads.is_empty()
+    }
+
+    pub fn dimension_count(&self) -> usize {
+        self.foreign_keys.len()
+    }
+
+    fn row_keys(&self, row: usize) -> Vec<u64> {
+        self.foreign_keys.iter().map(|column| column[row]).collect()
+    }
+}
+
+// Wide output row. foreign_keys and dimension_payloads hold one entry per
+// dimension, in the order the dimensions were given.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct StarJoined {
+    pub fact_payload: u64,
+    pub foreign_keys: Vec<u64>,
+    pub dimension_payloads: Vec<u64>
+}
+
+pub fn nested_loop_star_join(fact: &FactTable, dimensions: &[Vec<Tuple>]) -> Vec<StarJoined> {
+    assert!(fact.dimension_count() == dimensions.len());
+
+    let mut output = Vec::new();
+    for row in 0..fact.len() {
+        // Every combination of matching dimension payloads
+        let mut combinations: Vec<Vec<u64>> = vec![Vec::new()];
+        for (column, dimension) in fact.foreign_keys.iter().zip(dimensions) {
+            let matches: Vec<u64> = dimension.iter()
+                .filter(|t| t.key == column[row])
+                .map(|t| t.payload)
+                .collect();
+            combinations = combinations.into_iter()
+                .flat_map(|c| matches.iter().map(move |p| [c.as_slice(), &[*p]].concat()))
+                .collect();
+        }
+        for dimension_payloads in combinations {
+            output.push(StarJoined {fact_payload: fact.payloads[row], foreign_keys: fact.row_keys(row), dimension_payloads});
+        }
+    }
+    output
+}
+
+pub fn star_join(fact: &FactTable, dimensions: Vec<Vec<Tuple>>, thread_count: usize) -> Vec<Vec<StarJoined>> {
+    star_join_into(fact, dimensions, thread_count, Vec::new)
+}
+
+// Parallel star join. Every dimension is sorted once, on its own worker, and
+// shared by all workers. The fact table is split into thread_count row ranges
+// once; a worker then joins its rows with one dimension after the other,
+// carrying only the rows that matched so far into the next join, and builds
+// the wide rows at the end.
+pub fn star_join_into<S, F>(fact: &FactTable, mut dimensions: Vec<Vec<Tuple>>, thread_count: usize, make_sink: F) -> Vec<S>
+where
+    S: JoinSink<StarJoined> + Send,
+    F: Fn() -> S + Sync
+{
+    assert!(thread_count > 0);
+    assert!(fact.dimension_count() == dimensions.len());
+
+    thread::scope(|s| {
+        for (worker, dimension) in dimensions.iter_mut().enumerate() {
+            affinity::spawn(s, worker, move || dimension.sort_by_key(|t| t.key));
+        }
+    });
+
+    let dimensions: &[Vec<Tuple>] = &dimensions;
+    let chunk_size = fact.len().div_ceil(thread_count).max(1);
+    let make_sink = &make_sink;
+
+    let mut outputs = Vec::new();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for (worker, start) in (0..fact.len()).step_by(chunk_size).enumerate() {
+            let rows = start..(start + chunk_size).min(fact.len());
+            handles.push(affinity::spawn(s, worker, move || {
+                let mut output = make_sink();
+                join_fact_rows(fact, rows, dimensions, &mut output);
+                output
+            }));
+        }
+        for h in handles {
+            outputs.push(h.join().unwrap());
+        }
+    });
+
+    outputs
+}
+
+// Joins a range of fact rows with the sorted dimensions.
+fn join_fact_rows<S: JoinSink<StarJoined>>(fact: &FactTable, rows: std::ops::Range<usize>, dimensions: &[Vec<Tuple>], output: &mut S) {
+    // Fact rows that matched every dimension so far, with their payloads
+    let mut partial: Vec<(usize, Vec<u64>)> = rows.map(|row| (row, Vec::with_capacity(dimensions.len()))).collect();
+
+    for (column, dimension) in fact.foreign_keys.iter().zip(dimensions) {
+        if partial.is_empty() {
+            return;
+        }
+
+        // The payload of a probe is its index in partial
+        let mut probes: Vec<Tuple> = partial.iter().enumerate()
+            .map(|(i, (row, _))| Tuple::new(column[*row], i as u64))
+            .collect();
+        probes.sort_by_key(|t| t.key);
+
+        let mut matches: Vec<(usize, u64)> = Vec::new();
+        join::merge_join_sorted(&probes, dimension, &mut FnSink(|j: Joined| matches.push((j.left_payload as usize, j.right_payload))));
+        matches.sort_unstable_by_key(|m| m.0);
+
+        partial = matches.into_iter()
+            .map(|(i, payload)| {
+                let (row, payloads) = &partial[i];
+                let mut payloads = payloads.clone();
+                payloads.push(payload);
+                (*row, payloads)
+            })
+            .collect();
+    }
+
+    for (row, dimension_payloads) in partial {
+        output.push(StarJoined {fact_payload: fact.payloads[row], foreign_keys: fact.row_keys(row), dimension_payloads});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    // A fact table with one foreign key column per dimension. About 1 in 10
+    // keys of every column misses its dimension.
+    fn gen_star<R: Rng>(fact_len: usize, dimensions: &[Vec<Tuple>], rng: &mut R) -> FactTable {
+        let foreign_keys = dimensions.iter()
+            .map(|dimension| (0..fact_len)
+                .map(|_| if rng.random_bool(0.1) { rng.random() } else { dimension.choose(rng).unwrap().key })
+                .collect())
+            .collect();
+        FactTable::new(foreign_keys, infrastructure::gen_keys(fact_len, rng))
+    }
+
+    #[test]
+    fn star_join_test() {
+        let fact = FactTable::new(vec![vec![1, 2, 3], vec![7, 8, 7]], vec![100, 200, 300]);
+        let dimensions = vec![
+            vec![Tuple::new(1, 10), Tuple::new(3, 30)],
+            vec![Tuple::new(7, 70), Tuple::new(8, 80), Tuple::new(7, 71)]
+        ];
+
+        let mut output = star_join(&fact, dimensions, 2).into_iter().flatten().collect::<Vec<StarJoined>>();
+        output.sort_by_key(|r| (r.fact_payload, r.dimension_payloads.clone()));
+        let row = |fact_payload, keys: [u64; 2], payloads: [u64; 2]| StarJoined {fact_payload, foreign_keys: keys.to_vec(), dimension_payloads: payloads.to_vec()};
+        assert_eq!(output, vec![
+            row(100, [1, 7], [10, 70]),
+            row(100, [1, 7], [10, 71]),
+            row(300, [3, 7], [30, 70]),
+            row(300, [3, 7], [30, 71])
+        ]);
+    }
+
+    #[test]
+    fn compare_star_join_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let mut dimensions: Vec<Vec<Tuple>> = [300, 50, 1000].iter()
+            .map(|n| infrastructure::gen_table(*n, &mut rng))
+            .collect();
+        // One dimension with duplicate keys
+        let duplicates: Vec<Tuple> = dimensions[1].iter().map(|t| Tuple::new(t.key, !t.payload)).collect();
+        dimensions[1].extend(duplicates);
+
+        let fact = gen_star(3000, &dimensions, &mut rng);
+        let expected = nested_loop_star_join(&fact, &dimensions);
+        assert!(!expected.is_empty());
+
+        for thread_count in [1, 4] {
+            let output = star_join(&fact, dimensions.clone(), thread_count)
+                .into_iter().flatten().collect::<Vec<StarJoined>>();
+            assert!(infrastructure::table_eq(&expected, &output));
+        }
+    }
+
+    #[test]
+    fn star_join_matches_chained_joins() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let dimensions: Vec<Vec<Tuple>> = (0..2).map(|_| infrastructure::gen_table(500, &mut rng)).collect();
+        // Fact payloads are the row numbers
+        let fact = gen_star(5000, &dimensions, &mut rng);
+        let fact = FactTable::new(fact.foreign_keys, (0..fact.payloads.len() as u64).collect());
+
+        // Chain two binary joins. The second one is keyed on the second
+        // foreign key of each row of the first output, carrying its index.
+        let rows: Vec<Tuple> = (0..fact.len()).map(|i| Tuple::new(fact.foreign_keys[0][i], i as u64)).collect();
+        let first = join::basic_sort_merge_join(rows, dimensions[0].clone());
+        let rekeyed: Vec<Tuple> = first.iter().enumerate()
+            .map(|(i, j)| Tuple::new(fact.foreign_keys[1][j.left_payload as usize], i as u64))
+            .collect();
+        let expected: Vec<(u64, u64, u64)> = join::basic_sort_merge_join(rekeyed, dimensions[1].clone()).iter()
+            .map(|j| {
+                let f = &first[j.left_payload as usize];
+                (f.left_payload, f.right_payload, j.right_payload)
+            })
+            .collect();
+        assert!(!expected.is_empty());
+
+        let output: Vec<(u64, u64, u64)> = star_join(&fact, dimensions, 3).into_iter().flatten()
+            .map(|r| (r.fact_payload, r.dimension_payloads[0], r.dimension_payloads[1]))
+            .collect();
+        assert!(infrastructure::table_eq(&expected, &output));
+    }
+}
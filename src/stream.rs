@@ -0,0 +1,451 @@
+use std::{cmp::Ordering, collections::{HashMap, VecDeque}, ops::Range, thread};
+
+use crate::{affinity, join, parallel, sink::JoinSink, tuples::{Joined, Tuple}};
+
+// Resumable merge join over two sorted inputs. Matches are produced one at a
+// time, so a consumer can stop, count or aggregate without materializing the
+// output, even in the middle of the cross product of a duplicate key.
+#[derive(Clone, Debug)]
+pub struct MergeJoinIter<'a> {
+    left: &'a [Tuple],
+    right: &'a [Tuple],
+    // Merge cursors, positioned just past the current group of equal keys
+    li: usize,
+    ri: usize,
+    // Current group of equal keys and the position in its cross product
+    l_group: Range<usize>,
+    r_group: Range<usize>,
+    i: usize,
+    j: usize
+}
+
+impl<'a> MergeJoinIter<'a> {
+    pub fn new(left: &'a [Tuple], right: &'a [Tuple]) -> MergeJoinIter<'a> {
+        let (left, right) = join::overlap(left, right);
+        MergeJoinIter {left, right, li: 0, ri: 0, l_group: 0..0, r_group: 0..0, i: 0, j: 0}
+    }
+
+    // Moves the merge cursors to the next key found in both inputs. Returns
+    // false once either input is exhausted.
+    fn next_group(&mut self) -> bool {
+        let (left, right) = (self.left, self.right);
+
+        while self.li < left.len() && self.ri < right.len() {
+            match left[self.li].key.cmp(&right[self.ri].key) {
+                Ordering::Less => {self.li += 1;}
+                Ordering::Greater => {self.ri += 1;}
+                Ordering::Equal => {
+                    let key = left[self.li].key;
+
+                    let l_start = self.li;
+                    while self.li < left.len() && left[self.li].key == key { self.li += 1; }
+
+                    let r_start = self.ri;
+                    while self.ri < right.len() && right[self.ri].key == key { self.ri += 1; }
+
+                    self.l_group = l_start..self.li;
+                    self.r_group = r_start..self.ri;
+                    self.i = l_start;
+                    self.j = r_start;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Iterator for MergeJoinIter<'_> {
+    type Item = Joined;
+
+    #[inline]
+    fn next(&mut self) -> Option<Joined> {
+        if self.i >= self.l_group.end && !self.next_group() {
+            return None;
+        }
+
+        let lt = &self.left[self.i];
+        let rt = &self.right[self.j];
+
+        self.j += 1;
+        if self.j == self.r_group.end {
+            self.j = self.r_group.start;
+            self.i += 1;
+        }
+
+        Some(Joined::new(lt.key, lt.payload, rt.payload))
+    }
+}
+
+// MPSM join whose phase 4 runs lazily. Building the stream sorts the public
+// runs and sorts (and for the partitioned variants, partitions) the private
+// input in parallel. The merge of every private partition against the public
+// runs is then exposed as one iterator per worker, so consumers can pipeline,
+// count or aggregate the output on their own threads.
+pub struct MpsmStream {
+    private_partitions: Vec<Vec<Tuple>>,
+    // The private chunk of every worker: a partition and a range within it.
+    // The chunks of a partition are consecutive and in order.
+    private_chunks: Vec<(usize, Range<usize>)>,
+    public: Vec<Tuple>,
+    public_chunk_size: usize
+}
+
+impl MpsmStream {
+    // basic_mpsm: every private chunk is merged with the entire public input.
+    pub fn basic(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize) -> MpsmStream {
+        assert!(thread_count > 0);
+
+        let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+        parallel::sort_runs_parallel(&mut right, thread_count);
+
+        // The private input stays one partition, cut into chunks in place
+        let private_chunk_size = left.len().div_ceil(thread_count).max(1);
+        let private_chunks = (0..left.len()).step_by(private_chunk_size)
+            .map(|start| (0, start..(start + private_chunk_size).min(left.len())))
+            .collect();
+
+        MpsmStream::sorted(vec![left], private_chunks, right, public_chunk_size)
+    }
+
+    // partitioned_mpsm: the private input is radix partitioned on the top key bits.
+    pub fn partitioned(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize) -> MpsmStream {
+        assert!(thread_count > 0);
+
+        let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+        parallel::sort_runs_parallel(&mut right, thread_count);
+
+        let private_partitions = join::radix_partition_private(&left, thread_count);
+
+        MpsmStream::whole_partitions(private_partitions, right, public_chunk_size)
+    }
+
+    // range_partitioned_mpsm: the private input is range partitioned on
+    // splitters taken from the public runs.
+    pub fn range_partitioned(left: Vec<Tuple>, mut right: Vec<Tuple>, thread_count: usize) -> MpsmStream {
+        assert!(thread_count > 0);
+
+        let public_chunk_size = right.len().div_ceil(thread_count).max(1);
+        parallel::sort_runs_parallel(&mut right, thread_count);
+
+        let (private_partitions, _) = join::range_partition_private(&left, &right, public_chunk_size, thread_count);
+
+        MpsmStream::whole_partitions(private_partitions, right, public_chunk_size)
+    }
+
+    // One worker per private partition.
+    fn whole_partitions(private_partitions: Vec<Vec<Tuple>>, public: Vec<Tuple>, public_chunk_size: usize) -> MpsmStream {
+        let private_chunks = private_partitions.iter().enumerate()
+            .map(|(i, p)| (i, 0..p.len()))
+            .collect();
+        MpsmStream::sorted(private_partitions, private_chunks, public, public_chunk_size)
+    }
+
+    // Phase 3: sort every private chunk on its own worker.
+    fn sorted(mut private_partitions: Vec<Vec<Tuple>>, private_chunks: Vec<(usize, Range<usize>)>, public: Vec<Tuple>, public_chunk_size: usize) -> MpsmStream {
+        thread::scope(|s| {
+            let mut rest: Vec<&mut [Tuple]> = private_partitions.iter_mut().map(|p| p.as_mut_slice()).collect();
+            for (worker, (partition, range)) in private_chunks.iter().enumerate() {
+                let (private_chunk, tail) = std::mem::take(&mut rest[*partition]).split_at_mut(range.len());
+                rest[*partition] = tail;
+                affinity::spawn(s, worker, move || private_chunk.sort_by_key(|t| t.key));
+            }
+        });
+
+        MpsmStream {private_partitions, private_chunks, public, public_chunk_size}
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.private_chunks.len()
+    }
+
+    // Phase 4 of one worker: the matches of private chunk `worker` against
+    // every public run, produced on demand.
+    pub fn worker(&self, worker: usize) -> impl Iterator<Item = Joined> + Send + '_ {
+        let (partition, range) = &self.private_chunks[worker];
+        let private: &[Tuple] = &self.private_partitions[*partition][range.clone()];
+        self.public.chunks(self.public_chunk_size)
+            .flat_map(move |run| MergeJoinIter::new(private, run))
+    }
+
+    pub fn workers(&self) -> Vec<impl Iterator<Item = Joined> + Send + '_> {
+        (0..self.worker_count()).map(|w| self.worker(w)).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right
+}
+
+// Which buffered tuple a SymmetricHashJoin drops once it holds `capacity`
+// tuples. An evicted tuple no longer joins with tuples that arrive later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    // The tuple that arrived first, on either side: a sliding window over the
+    // combined input.
+    Oldest,
+    // The oldest tuple of the side that currently buffers more tuples, which
+    // keeps the smaller side (e.g. a dimension table) complete for longer.
+    OldestOfLarger
+}
+
+// Symmetric (pipelined) hash join (Wilschut and Apers, 1991). Both inputs are
+// hashed as they arrive. A new tuple first probes the table of the other side,
+// emitting its matches right away, and is then inserted into the table of its
+// own side. Without a capacity every pair is emitted exactly once, whatever
+// the interleaving of the batches.
+pub struct SymmetricHashJoin {
+    left: HashMap<u64, VecDeque<u64>>,
+    right: HashMap<u64, VecDeque<u64>>,
+    // Arrival order of the buffered tuples of each side
+    left_order: VecDeque<u64>,
+    right_order: VecDeque<u64>,
+    // Arrival order across both sides, for Eviction::Oldest
+    arrivals: VecDeque<Side>,
+    capacity: Option<(usize, Eviction)>,
+    evicted: usize
+}
+
+impl SymmetricHashJoin {
+    // Buffers every tuple. The output is the complete join.
+    pub fn new() -> SymmetricHashJoin {
+        SymmetricHashJoin {
+            left: HashMap::new(),
+            right: HashMap::new(),
+            left_order: VecDeque::new(),
+            right_order: VecDeque::new(),
+            arrivals: VecDeque::new(),
+            capacity: None,
+            evicted: 0
+        }
+    }
+
+    // Buffers at most `capacity` tuples over both sides.
+    pub fn with_capacity(capacity: usize, eviction: Eviction) -> SymmetricHashJoin {
+        assert!(capacity > 0);
+        SymmetricHashJoin {capacity: Some((capacity, eviction)), ..SymmetricHashJoin::new()}
+    }
+
+    pub fn push_left<S: JoinSink<Joined>>(&mut self, batch: &[Tuple], output: &mut S) {
+        for t in batch {
+            if let Some(payloads) = self.right.get(&t.key) {
+                for p in payloads {
+                    output.push(Joined::new(t.key, t.payload, *p));
+                }
+            }
+            self.insert(Side::Left, *t);
+        }
+    }
+
+    pub fn push_right<S: JoinSink<Joined>>(&mut self, batch: &[Tuple], output: &mut S) {
+        for t in batch {
+            if let Some(payloads) = self.left.get(&t.key) {
+                for p in payloads {
+                    output.push(Joined::new(t.key, *p, t.payload));
+                }
+            }
+            self.insert(Side::Right, *t);
+        }
+    }
+
+    fn insert(&mut self, side: Side, t: Tuple) {
+        if let Some((capacity, eviction)) = self.capacity {
+            if self.buffered() == capacity {
+                let victim = match eviction {
+                    Eviction::Oldest => self.arrivals.pop_front().unwrap(),
+                    Eviction::OldestOfLarger => {
+                        if self.left_order.len() >= self.right_order.len() { Side::Left } else { Side::Right }
+                    }
+                };
+                self.evict(victim);
+            }
+            if eviction == Eviction::Oldest {
+                self.arrivals.push_back(side);
+            }
+        }
+
+        let (table, order) = match side {
+            Side::Left => (&mut self.left, &mut self.left_order),
+            Side::Right => (&mut self.right, &mut self.right_order)
+        };
+        table.entry(t.key).or_default().push_back(t.payload);
+        order.push_back(t.key);
+    }
+
+    // Drops the oldest buffered tuple of a side. Tuples of a key are kept in
+    // arrival order, so it is the front of its key's payloads.
+    fn evict(&mut self, side: Side) {
+        let (table, order) = match side {
+            Side::Left => (&mut self.left, &mut self.left_order),
+            Side::Right => (&mut self.right, &mut self.right_order)
+        };
+        let key = order.pop_front().unwrap();
+        let payloads = table.get_mut(&key).unwrap();
+        payloads.pop_front();
+        if payloads.is_empty() {
+            table.remove(&key);
+        }
+        self.evicted += 1;
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.left_order.len() + self.right_order.len()
+    }
+
+    pub fn buffered_side(&self, side: Side) -> usize {
+        match side {
+            Side::Left => self.left_order.len(),
+            Side::Right => self.right_order.len()
+        }
+    }
+
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+}
+
+impl Default for SymmetricHashJoin {
+    fn default() -> SymmetricHashJoin {
+        SymmetricHashJoin::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn merge_join_iter_resumes_inside_cross_product() {
+        let left = vec![Tuple::new(1, 1), Tuple::new(2, 2), Tuple::new(2, 3), Tuple::new(4, 4)];
+        let right = vec![Tuple::new(2, 5), Tuple::new(2, 6), Tuple::new(2, 7), Tuple::new(4, 8)];
+
+        let mut iter = MergeJoinIter::new(&left, &right);
+        let first: Vec<Joined> = iter.by_ref().take(4).collect();
+        assert_eq!(first, vec![
+            Joined::new(2, 2, 5),
+            Joined::new(2, 2, 6),
+            Joined::new(2, 2, 7),
+            Joined::new(2, 3, 5)
+        ]);
+
+        let rest: Vec<Joined> = iter.by_ref().collect();
+        assert_eq!(rest, vec![
+            Joined::new(2, 3, 6),
+            Joined::new(2, 3, 7),
+            Joined::new(4, 4, 8)
+        ]);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn merge_join_iter_empty() {
+        let tuples = vec![Tuple::new(1, 1)];
+        assert_eq!(MergeJoinIter::new(&[], &tuples).next(), None);
+        assert_eq!(MergeJoinIter::new(&tuples, &[]).next(), None);
+        assert_eq!(MergeJoinIter::new(&tuples, &[Tuple::new(2, 2)]).next(), None);
+    }
+
+    #[test]
+    fn compare_mpsm_streams_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+        let nl_output = join::nested_loop_join(&lt, &rt);
+
+        let streams = [
+            MpsmStream::basic(lt.clone(), rt.clone(), 4),
+            MpsmStream::partitioned(lt.clone(), rt.clone(), 4),
+            MpsmStream::range_partitioned(lt.clone(), rt.clone(), 4)
+        ];
+        for stream in &streams {
+            // Count on the worker threads without keeping any output
+            let count: usize = thread::scope(|s| {
+                let handles: Vec<_> = stream.workers().into_iter()
+                    .map(|w| s.spawn(move || w.count()))
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).sum()
+            });
+            assert_eq!(count, nl_output.len());
+
+            let output: Vec<Joined> = stream.workers().into_iter().flatten().collect();
+            assert!(infrastructure::table_eq(&nl_output, &output));
+        }
+    }
+
+    // Feeds both tables to the join in randomly sized, randomly interleaved
+    // batches.
+    fn push_interleaved<R: Rng>(join: &mut SymmetricHashJoin, left: &[Tuple], right: &[Tuple], rng: &mut R) -> Vec<Joined> {
+        let mut output = Vec::new();
+        let (mut li, mut ri) = (0, 0);
+        while li < left.len() || ri < right.len() {
+            let n = rng.random_range(1..200);
+            if ri == right.len() || (li < left.len() && rng.random_bool(0.5)) {
+                let end = (li + n).min(left.len());
+                join.push_left(&left[li..end], &mut output);
+                li = end;
+            } else {
+                let end = (ri + n).min(right.len());
+                join.push_right(&right[ri..end], &mut output);
+                ri = end;
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn symmetric_hash_join_emits_on_arrival() {
+        let mut join = SymmetricHashJoin::new();
+        let mut output = Vec::new();
+
+        join.push_left(&[Tuple::new(1, 10), Tuple::new(2, 20)], &mut output);
+        assert!(output.is_empty());
+        join.push_right(&[Tuple::new(2, 5)], &mut output);
+        assert_eq!(output, vec![Joined::new(2, 20, 5)]);
+        join.push_left(&[Tuple::new(2, 21)], &mut output);
+        assert_eq!(output, vec![Joined::new(2, 20, 5), Joined::new(2, 21, 5)]);
+    }
+
+    #[test]
+    fn compare_symmetric_hash_join_sort_merge() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+        let expected = join::basic_sort_merge_join(lt.clone(), rt.clone());
+
+        let mut unbounded = SymmetricHashJoin::new();
+        let output = push_interleaved(&mut unbounded, &lt, &rt, &mut rng);
+        assert!(infrastructure::table_eq(&expected, &output));
+        assert_eq!(unbounded.buffered(), lt.len() + rt.len());
+
+        // A capacity that is never reached changes nothing
+        let mut roomy = SymmetricHashJoin::with_capacity(lt.len() + rt.len(), Eviction::Oldest);
+        let output = push_interleaved(&mut roomy, &lt, &rt, &mut rng);
+        assert!(infrastructure::table_eq(&expected, &output));
+        assert_eq!(roomy.evicted(), 0);
+    }
+
+    #[test]
+    fn symmetric_hash_join_eviction() {
+        let mut rng = StdRng::seed_from_u64(101);
+        let (lt, rt) = infrastructure::gen_tables(10000, 0.7, &mut rng);
+        let expected: HashSet<Joined> = join::basic_sort_merge_join(lt.clone(), rt.clone()).into_iter().collect();
+
+        for eviction in [Eviction::Oldest, Eviction::OldestOfLarger] {
+            let mut join = SymmetricHashJoin::with_capacity(5000, eviction);
+            let output = push_interleaved(&mut join, &lt, &rt, &mut rng);
+
+            assert_eq!(join.buffered(), 5000);
+            assert_eq!(join.evicted(), lt.len() + rt.len() - 5000);
+            // Every emitted row is correct and emitted once, but rows are lost
+            assert!(output.iter().all(|j| expected.contains(j)), "{eviction:?}");
+            assert_eq!(output.iter().collect::<HashSet<_>>().len(), output.len());
+            assert!(output.len() < expected.len());
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::thread;
+
+use crate::{affinity, search, tuples::Tuple};
+
+// Leapfrog triejoin (Veldhuizen, 2014), a worst-case optimal join for cyclic
+// queries. Instead of joining two relations at a time it binds one query
+// variable after the other, intersecting the values of every relation that
+// contains the variable. A relation of tuples is a trie of depth two: the
+// keys, and below each key its payloads.
+
+// Cursor over a relation sorted by (key, payload). Depth 0 is above the root,
+// depth 1 walks the distinct keys and depth 2 the payloads of the current key.
+#[derive(Clone, Debug)]
+pub struct TrieIterator<'a> {
+    relation: &'a [Tuple],
+    depth: usize,
+    pos: usize,
+    end: usize,
+    // Key level cursor while the payload level is open
+    key_pos: usize,
+    key_end: usize
+}
+
+impl<'a> TrieIterator<'a> {
+    pub fn new(relation: &'a [Tuple]) -> TrieIterator<'a> {
+        assert!(relation.is_sorted_by_key(|t| (t.key, t.payload)), "relation must be sorted by key and payload");
+        TrieIterator {relation, depth: 0, pos: 0, end: relation.len(), key_pos: 0, key_end: 0}
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.end
+    }
+
+    pub fn key(&self) -> u64 {
+        let t = &self.relation[self.pos];
+        if self.depth == 1 { t.key } else { t.payload }
+    }
+
+    pub fn open(&mut self) {
+        match self.depth {
+            0 => {
+                self.pos = 0;
+                self.end = self.relation.len();
+            }
+            1 => {
+                let key = self.key();
+                (self.key_pos, self.key_end) = (self.pos, self.end);
+                self.end = self.lower_bound(key.checked_add(1));
+            }
+            _ => panic!("tuple relations have two levels")
+        }
+        self.depth += 1;
+    }
+
+    pub fn up(&mut self) {
+        assert!(self.depth > 0);
+        if self.depth == 2 {
+            (self.pos, self.end) = (self.key_pos, self.key_end);
+        }
+        self.depth -= 1;
+    }
+
+    // Moves to the next distinct value of the current level.
+    pub fn next(&mut self) {
+        self.pos = self.lower_bound(self.key().checked_add(1));
+    }
+
+    // Moves to the first value at or past target. Seeks only move forward.
+    pub fn seek(&mut self, target: u64) {
+        self.pos = self.lower_bound(Some(target));
+    }
+
+    // Position of the first value at or past target, from the cursor on. The
+    // cursor advances by small steps most of the time, so the search gallops.
+    fn lower_bound(&self, target: Option<u64>) -> usize {
+        let Some(target) = target else { return self.end };
+        let rest = &self.relation[self.pos..self.end];
+        let found = if self.depth == 1 {
+            search::lb_exponential_search_by_key(&target, rest, |t| &t.key)
+        } else {
+            search::lb_exponential_search_by_key(&target, rest, |t| &t.payload)
+        };
+        found.map_or(self.end, |i| self.pos + i)
+    }
+}
+
+// Join of several trie iterators. variables[v] lists the iterators that
+// contain variable v. Every iterator must meet its variables in the order of
+// its levels: its key before its payload.
+pub struct LeapfrogTriejoin<'a> {
+    iterators: Vec<TrieIterator<'a>>,
+    variables: Vec<Vec<usize>>
+}
+
+impl<'a> LeapfrogTriejoin<'a> {
+    pub fn new(iterators: Vec<TrieIterator<'a>>, variables: Vec<Vec<usize>>) -> LeapfrogTriejoin<'a> {
+        assert!(variables.iter().all(|v| !v.is_empty() && v.iter().all(|i| *i < iterators.len())));
+        LeapfrogTriejoin {iterators, variables}
+    }
+
+    // Calls emit with the values of all variables for every result.
+    pub fn run<F: FnMut(&[u64])>(&mut self, mut emit: F) {
+        let mut binding = Vec::with_capacity(self.variables.len());
+        self.join_variable(0, &mut binding, &mut emit);
+    }
+
+    fn join_variable<F: FnMut(&[u64])>(&mut self, variable: usize, binding: &mut Vec<u64>, emit: &mut F) {
+        if variable == self.variables.len() {
+            emit(binding);
+            return;
+        }
+
+        let mut members = self.variables[variable].clone();
+        for i in &members {
+            self.iterators[*i].open();
+        }
+
+        if !members.iter().any(|i| self.iterators[*i].at_end()) {
+            members.sort_by_key(|i| self.iterators[*i].key());
+            let k = members.len();
+            let mut p = 0;
+            let mut max = self.iterators[members[k - 1]].key();
+
+            'search: loop {
+                // Seek the smallest iterator to the largest key until all agree
+                loop {
+                    let it = &mut self.iterators[members[p]];
+                    if it.key() == max {
+                        break;
+                    }
+                    it.seek(max);
+                    if it.at_end() {
+                        break 'search;
+                    }
+                    max = it.key();
+                    p = (p + 1) % k;
+                }
+
+                binding.push(max);
+                self.join_variable(variable + 1, binding, emit);
+                binding.pop();
+
+                let it = &mut self.iterators[members[p]];
+                it.next();
+                if it.at_end() {
+                    break;
+                }
+                max = it.key();
+                p = (p + 1) % k;
+            }
+        }
+
+        for i in &members {
+            self.iterators[*i].up();
+        }
+    }
+}
+
+// Q(a, b, c) = R(a, b), S(b, c), T(a, c) over relations sorted by key and
+// payload.
+pub fn leapfrog_triangles<F: FnMut(u64, u64, u64)>(r: &[Tuple], s: &[Tuple], t: &[Tuple], mut emit: F) {
+    let iterators = vec![TrieIterator::new(r), TrieIterator::new(s), TrieIterator::new(t)];
+    let variables = vec![vec![0, 2], vec![0, 1], vec![1, 2]];
+    LeapfrogTriejoin::new(iterators, variables).run(|v| emit(v[0], v[1], v[2]));
+}
+
+pub fn nested_loop_triangles(r: &[Tuple], s: &[Tuple], t: &[Tuple]) -> Vec<(u64, u64, u64)> {
+    let mut output = Vec::new();
+    for ab in r {
+        for bc in s.iter().filter(|bc| bc.key == ab.payload) {
+            for _ in t.iter().filter(|ac| ac.key == ab.key && ac.payload == bc.payload) {
+                output.push((ab.key, ab.payload, bc.payload));
+            }
+        }
+    }
+    output
+}
+
+// Edges of an undirected graph as (smaller, larger) vertex pairs, sorted and
+// without self loops or duplicates.
+pub fn orient_edges(edges: &[Tuple]) -> Vec<Tuple> {
+    let mut oriented: Vec<Tuple> = edges.iter()
+        .filter(|e| e.key != e.payload)
+        .map(|e| Tuple::new(e.key.min(e.payload), e.key.max(e.payload)))
+        .collect();
+    oriented.sort_unstable_by_key(|e| (e.key, e.payload));
+    oriented.dedup();
+    oriented
+}
+
+// Number of triangles of the undirected graph with the given edges. With
+// oriented edges, Q(a, b, c) = E(a, b), E(b, c), E(a, c) finds every triangle
+// once, as a < b < c. The values of a are split across thread_count workers
+// at key boundaries; E(a, b) and E(a, c) only need the edges of a worker's a
+// values, E(b, c) needs all of them.
+pub fn count_triangles(edges: &[Tuple], thread_count: usize) -> u64 {
+    assert!(thread_count > 0);
+
+    let oriented = orient_edges(edges);
+    let chunk_size = oriented.len().div_ceil(thread_count).max(1);
+
+    let mut bounds = vec![0];
+    while *bounds.last().unwrap() < oriented.len() {
+        let end = (bounds.last().unwrap() + chunk_size).min(oriented.len());
+        // Extend the chunk to the end of its last key
+        let end = end + oriented[end..].partition_point(|e| e.key == oriented[end - 1].key);
+        bounds.push(end);
+    }
+
+    let oriented = &oriented;
+    thread::scope(|s| {
+        let handles: Vec<_> = bounds.windows(2).enumerate()
+            .map(|(worker, w)| affinity::spawn(s, worker, move || {
+                let chunk = &oriented[w[0]..w[1]];
+                let mut count = 0;
+                leapfrog_triangles(chunk, oriented, chunk, |_, _, _| count += 1);
+                count
+            }))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::infrastructure;
+
+    use super::*;
+
+    #[test]
+    fn trie_iterator_walks_levels() {
+        let relation = [Tuple::new(1, 5), Tuple::new(1, 7), Tuple::new(4, 2), Tuple::new(9, 3), Tuple::new(9, 8)];
+        let mut it = TrieIterator::new(&relation);
+
+        it.open();
+        assert_eq!(it.key(), 1);
+        it.open();
+        assert_eq!(it.key(), 5);
+        it.seek(6);
+        assert_eq!(it.key(), 7);
+        it.next();
+        assert!(it.at_end());
+        it.up();
+        it.next();
+        assert_eq!(it.key(), 4);
+        it.seek(5);
+        assert_eq!(it.key(), 9);
+        it.open();
+        it.seek(4);
+        assert_eq!(it.key(), 8);
+        it.up();
+        it.seek(10);
+        assert!(it.at_end());
+    }
+
+    #[test]
+    fn compare_leapfrog_triangles_nested_loop() {
+        let mut rng = StdRng::seed_from_u64(101);
+        // Three random edge relations, self loops included
+        let mut relations: Vec<Vec<Tuple>> = (0..3).map(|_| infrastructure::gen_edges(40, 400, &mut rng)).collect();
+        for relation in relations.iter_mut() {
+            relation.sort_unstable_by_key(|t| (t.key, t.payload));
+            relation.dedup();
+        }
+        let (r, s, t) = (&relations[0], &relations[1], &relations[2]);
+
+        let expected = nested_loop_triangles(r, s, t);
+        assert!(!expected.is_empty());
+
+        let mut output = Vec::new();
+        leapfrog_triangles(r, s, t, |a, b, c| output.push((a, b, c)));
+        assert!(infrastructure::table_eq(&expected, &output));
+    }
+
+    #[test]
+    fn count_triangles_test() {
+        // A 4-clique has 4 triangles, the extra edges close 3, 10, 11
+        let mut edges = Vec::new();
+        for a in 0..4 {
+            for b in 0..4 {
+                edges.push(Tuple::new(a, b));
+            }
+        }
+        edges.extend([Tuple::new(3, 10), Tuple::new(10, 11), Tuple::new(11, 3), Tuple::new(12, 3)]);
+        assert_eq!(count_triangles(&edges, 1), 5);
+
+        let mut rng = StdRng::seed_from_u64(101);
+        let edges = infrastructure::gen_edges(200, 3000, &mut rng);
+        let oriented = orient_edges(&edges);
+        let expected = nested_loop_triangles(&oriented, &oriented, &oriented).len() as u64;
+        assert!(expected > 0);
+        for thread_count in [1, 3, 8] {
+            assert_eq!(count_triangles(&edges, thread_count), expected);
+        }
+    }
+}
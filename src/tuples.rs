@@ -23,4 +23,73 @@ impl Joined {
     pub fn new(key: u64, left_payload: u64, right_payload: u64) -> Joined {
         Joined {key, left_payload, right_payload}
     }
-}
\ No newline at end of file
+}
+
+// Output row of an outer join. A missing payload marks the side that had no
+// matching tuple.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct OuterJoined {
+    pub key: u64,
+    pub left_payload: Option<u64>,
+    pub right_payload: Option<u64>
+}
+
+impl OuterJoined {
+    pub fn new(key: u64, left_payload: Option<u64>, right_payload: Option<u64>) -> OuterJoined {
+        OuterJoined {key, left_payload, right_payload}
+    }
+
+    pub fn left_only(t: &Tuple) -> OuterJoined {
+        OuterJoined::new(t.key, Some(t.payload), None)
+    }
+
+    pub fn right_only(t: &Tuple) -> OuterJoined {
+        OuterJoined::new(t.key, None, Some(t.payload))
+    }
+}
+
+impl From<Joined> for OuterJoined {
+    fn from(j: Joined) -> OuterJoined {
+        OuterJoined::new(j.key, Some(j.left_payload), Some(j.right_payload))
+    }
+}
+
+// Output row of a late materialized join: the row ids of the joined rows in
+// their tables, see late::RowIdSink.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct RowIdPair {
+    pub left_row: u64,
+    pub right_row: u64
+}
+
+impl RowIdPair {
+    pub fn new(left_row: u64, right_row: u64) -> RowIdPair {
+        RowIdPair {left_row, right_row}
+    }
+}
+
+impl From<Joined> for RowIdPair {
+    fn from(j: Joined) -> RowIdPair {
+        RowIdPair::new(j.left_payload, j.right_payload)
+    }
+}
+
+// Row id pair of an outer join. A missing row id marks the side that had no
+// matching row.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct RowIdOuterPair {
+    pub left_row: Option<u64>,
+    pub right_row: Option<u64>
+}
+
+impl RowIdOuterPair {
+    pub fn new(left_row: Option<u64>, right_row: Option<u64>) -> RowIdOuterPair {
+        RowIdOuterPair {left_row, right_row}
+    }
+}
+
+impl From<OuterJoined> for RowIdOuterPair {
+    fn from(j: OuterJoined) -> RowIdOuterPair {
+        RowIdOuterPair::new(j.left_payload, j.right_payload)
+    }
+}